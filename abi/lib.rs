@@ -0,0 +1,90 @@
+//! Everything the kernel and user programs have to agree on.
+#![no_std]
+
+/// File descriptor of the console, the only one there is for now.
+pub const STDOUT: usize = 1;
+
+/// System call numbers, passed in `a7`. Arguments go in `a0..a5`, the result comes back in `a0`.
+#[repr(usize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Syscall {
+    /// `write(fd, buf, len) -> bytes written`
+    Write = 1,
+    /// `exit(code) -> !`
+    Exit = 2,
+    /// `yield() -> 0`
+    Yield = 3,
+    /// `getpid() -> pid`
+    GetPid = 4,
+    /// `sleep(ms) -> 0`
+    Sleep = 5,
+    /// `sbrk(increment) -> previous end of the heap`
+    Sbrk = 6,
+}
+
+impl TryFrom<usize> for Syscall {
+    type Error = SyscallError;
+
+    fn try_from(value: usize) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(Syscall::Write),
+            2 => Ok(Syscall::Exit),
+            3 => Ok(Syscall::Yield),
+            4 => Ok(Syscall::GetPid),
+            5 => Ok(Syscall::Sleep),
+            6 => Ok(Syscall::Sbrk),
+            _ => Err(SyscallError::NoSuchSyscall),
+        }
+    }
+}
+
+/// Errors are returned as small negative numbers in `a0`.
+#[repr(isize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyscallError {
+    /// The syscall number in `a7` is not known.
+    NoSuchSyscall = -1,
+    /// A pointer argument does not point to memory the process can access.
+    BadAddress = -2,
+    /// The file descriptor does not exist.
+    BadFileDescriptor = -3,
+    /// An argument is out of range.
+    InvalidArgument = -4,
+    /// A reserved error code this version of the ABI doesn't know, e.g. from a newer kernel.
+    Unknown = -(MAX_ERROR as isize),
+}
+
+impl TryFrom<isize> for SyscallError {
+    /// The code, when it isn't one of ours.
+    type Error = isize;
+
+    fn try_from(value: isize) -> Result<Self, Self::Error> {
+        match value {
+            -1 => Ok(SyscallError::NoSuchSyscall),
+            -2 => Ok(SyscallError::BadAddress),
+            -3 => Ok(SyscallError::BadFileDescriptor),
+            -4 => Ok(SyscallError::InvalidArgument),
+            value if value == SyscallError::Unknown as isize => Ok(SyscallError::Unknown),
+            _ => Err(value),
+        }
+    }
+}
+
+/// The highest `MAX_ERROR` values of `a0` are reserved for errors.
+const MAX_ERROR: usize = 4095;
+
+pub type SyscallResult = Result<usize, SyscallError>;
+
+pub fn encode_result(result: SyscallResult) -> usize {
+    match result {
+        Ok(value) => value,
+        Err(error) => error as isize as usize,
+    }
+}
+
+pub fn decode_result(value: usize) -> SyscallResult {
+    if value <= usize::MAX - MAX_ERROR {
+        return Ok(value);
+    }
+    Err(SyscallError::try_from(value as isize).unwrap_or(SyscallError::Unknown))
+}
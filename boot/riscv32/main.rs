@@ -1,10 +1,14 @@
 #![no_std]
 #![no_main]
 use core::arch::asm;
+use oxiv_kernel::arch::{self, BOOT_PAGE_TABLE, DIRECT_MAP_OFFSET};
 use oxiv_kernel::{boot, BootInfo};
 // Based myself on https://github.com/starina-os/starina for the boot (specific) -> kernel (generic) -> arch (specific) structure
 //These are "filled in" by the linker
 extern "C" {
+    static __kernel_start: *const usize;
+    static __kernel_end: *const usize;
+
     static __text_start: *const usize;
     static __text_end: *const usize;
 
@@ -19,14 +23,14 @@ extern "C" {
 
     static __stack_start: *const usize;
     static __stack_end: *const usize;
-
-    static __heap_start: *const usize;
-    static __heap_end: *const usize;
 }
 
 /// Boot Entry point of our kernel.
 ///
-/// Sets the correct address in the stack pointer and jumps to the main function.
+/// The firmware starts us at the physical load address with paging off, while the kernel is linked in the
+/// direct map. So this turns paging on with `arch::BOOT_PAGE_TABLE`, jumps up to where we are linked,
+/// then sets the correct address in the stack pointer and jumps to the main function.
+/// The firmware's `a0` (hart id) and `a1` (device tree address) are passed through untouched.
 ///
 /// # Safety
 /// - This function must only be called during the kernel initialization phase.
@@ -37,8 +41,22 @@ extern "C" {
 pub unsafe extern "C" fn kernel_boot() -> ! {
     unsafe {
         asm!(
+            // `la` is relative to the pc, so until the jump it gives physical addresses
+            "la t0, {boot_table}",
+            "srli t0, t0, 12",
+            "li t1, 1 << 31",
+            "or t0, t0, t1",
+            "csrw satp, t0",
+            "sfence.vma",
+            "la t0, 1f",
+            "li t1, {offset}",
+            "add t0, t0, t1",
+            "jr t0",
+            "1:",
             "la sp, {stack_top}",
             "j {main}",
+            boot_table = sym BOOT_PAGE_TABLE,
+            offset = const DIRECT_MAP_OFFSET,
             stack_top = sym __stack_end,
             main = sym main,
             options(noreturn),
@@ -47,8 +65,10 @@ pub unsafe extern "C" fn kernel_boot() -> ! {
 }
 
 #[no_mangle]
-fn main() {
+extern "C" fn main(hart_id: usize, dtb_address: usize) {
     let boot_info = unsafe {
+        let kernel_start = convert_ptr_to_usize(&__kernel_start);
+        let kernel_end = convert_ptr_to_usize(&__kernel_end);
         let text_start = convert_ptr_to_usize(&__text_start);
         let text_end = convert_ptr_to_usize(&__text_end);
         let rodata_start = convert_ptr_to_usize(&__rodata_start);
@@ -59,9 +79,11 @@ fn main() {
         let bss_end = convert_ptr_to_usize(&__bss_end);
         let stack_start = convert_ptr_to_usize(&__stack_start);
         let stack_end = convert_ptr_to_usize(&__stack_end);
-        let heap_start = convert_ptr_to_usize(&__heap_start);
-        let heap_end = convert_ptr_to_usize(&__heap_end);
         BootInfo {
+            hart_id,
+            dtb_address: arch::phys_to_virt(dtb_address),
+            kernel_start,
+            kernel_end,
             text_start,
             text_end,
             rodata_start,
@@ -72,8 +94,6 @@ fn main() {
             bss_end,
             stack_start,
             stack_end,
-            heap_start,
-            heap_end,
         }
     };
     boot(&boot_info);
@@ -0,0 +1,477 @@
+use crate::arch::{self, Satp, PAGE_SIZE};
+use crate::page::PAGE_ALLOCATOR;
+use crate::page_table::{EntryFlags, PageTable, PhysicalAddress, VirtualAddress};
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use core::fmt::{self, Display};
+
+/// What fills the pages of a region. They are filled in on first touch, see `AddressSpace::handle_fault`.
+#[derive(Clone)]
+pub enum Backing {
+    /// Zeroed pages, owned by the address space.
+    Anonymous,
+    /// `data` copied to `offset` bytes into the region, everything else zeroed like a bss.
+    /// The pages are private copies owned by the address space.
+    File { data: &'static [u8], offset: usize },
+    /// Physical memory starting at this address, e.g. device registers. Never freed.
+    Physical(u64),
+    /// Frames that other address spaces can map too, starting at frame `first`.
+    Shared {
+        frames: Arc<SharedFrames>,
+        first: usize,
+    },
+}
+
+impl Backing {
+    /// The backing of the part of a region that starts `distance` bytes further in.
+    fn advanced(&self, distance: usize) -> Backing {
+        match self {
+            Backing::Anonymous => Backing::Anonymous,
+            Backing::File { data, offset } if distance <= *offset => Backing::File {
+                data,
+                offset: offset - distance,
+            },
+            Backing::File { data, offset } => Backing::File {
+                data: &data[(distance - offset).min(data.len())..],
+                offset: 0,
+            },
+            Backing::Physical(base) => Backing::Physical(base + distance as u64),
+            Backing::Shared { frames, first } => Backing::Shared {
+                frames: frames.clone(),
+                first: first + distance / PAGE_SIZE,
+            },
+        }
+    }
+
+    /// Whether the frames mapped for this backing belong to the address space.
+    fn owns_frames(&self) -> bool {
+        matches!(self, Backing::Anonymous | Backing::File { .. })
+    }
+}
+
+/// Zeroed frames that stay around for as long as some region maps them.
+pub struct SharedFrames {
+    frames: Vec<u64>,
+}
+
+impl SharedFrames {
+    pub fn new(pages: usize) -> Option<Arc<Self>> {
+        let mut shared = SharedFrames {
+            frames: Vec::with_capacity(pages),
+        };
+        for _ in 0..pages {
+            // Dropping what we have so far gives it back
+            shared.frames.push(alloc_frame()?);
+        }
+        Some(Arc::new(shared))
+    }
+
+    pub fn pages(&self) -> usize {
+        self.frames.len()
+    }
+}
+
+impl Drop for SharedFrames {
+    fn drop(&mut self) {
+        for &frame in &self.frames {
+            free_frame(frame);
+        }
+    }
+}
+
+/// A virtual memory area: the page aligned range [start, end) with the same flags and backing.
+#[derive(Clone)]
+pub struct Vma {
+    pub start: usize,
+    pub end: usize,
+    /// Entry flags of the pages, see `EntryFlags`.
+    pub flags: usize,
+    pub backing: Backing,
+}
+
+impl Vma {
+    pub fn contains(&self, address: usize) -> bool {
+        (self.start..self.end).contains(&address)
+    }
+
+    fn overlaps(&self, start: usize, end: usize) -> bool {
+        self.start < end && start < self.end
+    }
+
+    /// Cuts the region at `at`, keeping [start, at) and returning [at, end).
+    fn split_off(&mut self, at: usize) -> Vma {
+        let tail = Vma {
+            start: at,
+            end: self.end,
+            flags: self.flags,
+            backing: self.backing.advanced(at - self.start),
+        };
+        self.end = at;
+        tail
+    }
+}
+
+impl Display for Vma {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let flag = |flag: EntryFlags, c| {
+            if self.flags & flag as usize != 0 {
+                c
+            } else {
+                '-'
+            }
+        };
+        let backing = match &self.backing {
+            Backing::Anonymous => "anonymous",
+            Backing::File { .. } => "file",
+            Backing::Physical(_) => "physical",
+            Backing::Shared { .. } => "shared",
+        };
+        write!(
+            f,
+            "{:#010x}-{:#010x} {}{}{}{} {}",
+            self.start,
+            self.end,
+            flag(EntryFlags::Read, 'r'),
+            flag(EntryFlags::Write, 'w'),
+            flag(EntryFlags::Execute, 'x'),
+            flag(EntryFlags::User, 'u'),
+            backing
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmaError {
+    /// The range is empty, not page aligned, wraps around, reaches into the kernel half
+    /// or is longer than its backing.
+    InvalidRange,
+    /// Part of the range is already in a region.
+    Overlap,
+    /// Part of the range is in no region.
+    NotMapped,
+    /// The flags are not for user pages, or writable but not readable, which RISC-V reserves.
+    InvalidFlags,
+}
+
+/// Why a page fault could not be resolved, see `AddressSpace::handle_fault`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageFaultError {
+    /// The address is not in any region.
+    Unmapped,
+    /// The region does not allow this kind of access.
+    NotPermitted,
+    OutOfMemory,
+}
+
+/// What a faulting access tried to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Load,
+    Store,
+    Execute,
+}
+
+impl Access {
+    /// Entry flags a page needs to allow this access from user mode.
+    pub fn required_flags(self) -> usize {
+        let flag = match self {
+            Access::Load => EntryFlags::Read,
+            Access::Store => EntryFlags::Write,
+            Access::Execute => EntryFlags::Execute,
+        };
+        flag as usize | EntryFlags::User as usize
+    }
+}
+
+/// The user half of a process: its root page table and the regions it owns, ordered by address.
+/// Pages are only ever mapped inside a region, which is what lets them be freed per region.
+pub struct AddressSpace {
+    /// Shares the kernel mappings, see `PageTable::new_process`.
+    page_table: Box<PageTable>,
+    vmas: Vec<Vma>,
+}
+
+impl AddressSpace {
+    pub fn new() -> Self {
+        AddressSpace {
+            page_table: PageTable::new_process(&crate::ROOT_PAGE_TABLE.lock()),
+            vmas: Vec::new(),
+        }
+    }
+
+    pub fn satp(&self) -> Satp {
+        Satp::new(arch::virt_to_phys(
+            &*self.page_table as *const PageTable as usize,
+        ))
+    }
+
+    pub fn page_table(&self) -> &PageTable {
+        &self.page_table
+    }
+
+    pub fn regions(&self) -> impl Iterator<Item = &Vma> {
+        self.vmas.iter()
+    }
+
+    pub fn find(&self, address: usize) -> Option<&Vma> {
+        self.vmas.iter().find(|vma| vma.contains(address))
+    }
+
+    /// Whether any region overlaps [start, end).
+    pub fn overlaps(&self, start: usize, end: usize) -> bool {
+        self.vmas.iter().any(|vma| vma.overlaps(start, end))
+    }
+
+    /// Adds the region [start, end). Nothing is mapped yet, pages are filled in as they are touched
+    /// or by `populate`. A region right after one that only differs in its end grows that one instead.
+    pub fn map_region(
+        &mut self,
+        start: usize,
+        end: usize,
+        flags: usize,
+        backing: Backing,
+    ) -> Result<(), VmaError> {
+        Self::check_range(start, end)?;
+        Self::check_flags(flags)?;
+        if let Backing::Shared { frames, first } = &backing {
+            // Every page needs a frame to map, past the last one there is nothing to fault in
+            if first + (end - start) / PAGE_SIZE > frames.pages() {
+                return Err(VmaError::InvalidRange);
+            }
+        }
+        if self.overlaps(start, end) {
+            return Err(VmaError::Overlap);
+        }
+        let index = self.vmas.partition_point(|vma| vma.start < start);
+        if let Some(previous) = index.checked_sub(1).map(|i| &mut self.vmas[i]) {
+            if previous.end == start
+                && previous.flags == flags
+                && matches!(
+                    (&previous.backing, &backing),
+                    (Backing::Anonymous, Backing::Anonymous)
+                )
+            {
+                previous.end = end;
+                return Ok(());
+            }
+        }
+        self.vmas.insert(
+            index,
+            Vma {
+                start,
+                end,
+                flags,
+                backing,
+            },
+        );
+        Ok(())
+    }
+
+    /// Removes [start, end) from the regions it is in and unmaps its pages, splitting regions that stick out.
+    /// Parts of the range that are in no region are skipped.
+    pub fn unmap_region(&mut self, start: usize, end: usize) -> Result<(), VmaError> {
+        Self::check_range(start, end)?;
+        self.split_at(start);
+        self.split_at(end);
+        let mut index = 0;
+        while index < self.vmas.len() {
+            if self.vmas[index].overlaps(start, end) {
+                let vma = self.vmas.remove(index);
+                self.release_pages(&vma);
+            } else {
+                index += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Changes the flags of [start, end), which has to lie fully in regions, including the pages mapped already.
+    /// Flags without Read, Write and Execute take all access away, the pages stay mapped for when it comes back.
+    pub fn protect_region(
+        &mut self,
+        start: usize,
+        end: usize,
+        flags: usize,
+    ) -> Result<(), VmaError> {
+        Self::check_range(start, end)?;
+        Self::check_flags(flags)?;
+        if !self.is_covered(start, end) {
+            return Err(VmaError::NotMapped);
+        }
+        self.split_at(start);
+        self.split_at(end);
+        for vma in self.vmas.iter_mut().filter(|vma| vma.overlaps(start, end)) {
+            vma.flags = flags;
+        }
+        for page in (start..end).step_by(PAGE_SIZE) {
+            self.page_table.set_flags(&VirtualAddress(page), flags);
+        }
+        Ok(())
+    }
+
+    /// Fills in every page of [start, end) up front, instead of on first touch.
+    pub fn populate(
+        &mut self,
+        start: usize,
+        end: usize,
+        access: Access,
+    ) -> Result<(), PageFaultError> {
+        for page in (start & !(PAGE_SIZE - 1)..end).step_by(PAGE_SIZE) {
+            self.handle_fault(page, access)?;
+        }
+        Ok(())
+    }
+
+    /// Maps the page holding `address` from its region's backing, if the region allows `access`.
+    /// On success the faulting instruction can simply run again.
+    pub fn handle_fault(&mut self, address: usize, access: Access) -> Result<(), PageFaultError> {
+        let vma = self.find(address).ok_or(PageFaultError::Unmapped)?;
+        let required = access.required_flags();
+        if vma.flags & required != required {
+            return Err(PageFaultError::NotPermitted);
+        }
+        let page = address & !(PAGE_SIZE - 1);
+        if let Some(mapping) = self.page_table.walk(&VirtualAddress(page)) {
+            // Another hart filled it in while this one still had the old translation cached
+            if mapping.flags & required == required {
+                arch::flush_page(page);
+                return Ok(());
+            }
+            return Err(PageFaultError::NotPermitted);
+        }
+        let frame = Self::frame_for(vma, page).ok_or(PageFaultError::OutOfMemory)?;
+        let owns_frame = vma.backing.owns_frames();
+        let result = self
+            .page_table
+            .map(VirtualAddress(page), PhysicalAddress(frame), vma.flags);
+        if result.is_err() {
+            // Only a level 0 table can be missing, the page was not mapped
+            if owns_frame {
+                free_frame(frame);
+            }
+            return Err(PageFaultError::OutOfMemory);
+        }
+        Ok(())
+    }
+
+    /// Returns the physical address of `address` if it is mapped with at least `flags`.
+    pub fn translate(&self, address: usize, flags: usize) -> Option<PhysicalAddress> {
+        self.page_table.translate(&VirtualAddress(address), flags)
+    }
+
+    /// The frame to map at `page` of `vma`, allocating and filling it for backings that own their frames.
+    fn frame_for(vma: &Vma, page: usize) -> Option<u64> {
+        let distance = page - vma.start;
+        match &vma.backing {
+            Backing::Anonymous => alloc_frame(),
+            Backing::File { data, offset } => {
+                let frame = alloc_frame()?;
+                // The part of [offset, offset + len) that falls in this page
+                let copy_start = distance.max(*offset);
+                let copy_end = (distance + PAGE_SIZE).min(offset + data.len());
+                if copy_start < copy_end {
+                    unsafe {
+                        core::ptr::copy_nonoverlapping(
+                            data[copy_start - offset..].as_ptr(),
+                            (arch::phys_to_virt(frame as usize) as *mut u8)
+                                .add(copy_start - distance),
+                            copy_end - copy_start,
+                        );
+                    }
+                }
+                Some(frame)
+            }
+            Backing::Physical(base) => Some(base + distance as u64),
+            Backing::Shared { frames, first } => {
+                frames.frames.get(first + distance / PAGE_SIZE).copied()
+            }
+        }
+    }
+
+    /// Unmaps the pages of `vma`, freeing the frames it owns.
+    fn release_pages(&mut self, vma: &Vma) {
+        let owns_frames = vma.backing.owns_frames();
+        self.page_table
+            .unmap_range(
+                VirtualAddress(vma.start),
+                VirtualAddress(vma.end),
+                |_, frame| {
+                    if owns_frames {
+                        free_frame(frame.0);
+                    }
+                },
+            )
+            .expect("Regions are mapped in 4 KiB pages, there are no megapages to split");
+    }
+
+    /// Splits the region containing `at`, if any, so that a region starts there.
+    fn split_at(&mut self, at: usize) {
+        if let Some(index) = self
+            .vmas
+            .iter()
+            .position(|vma| vma.start < at && at < vma.end)
+        {
+            let tail = self.vmas[index].split_off(at);
+            self.vmas.insert(index + 1, tail);
+        }
+    }
+
+    /// Whether every address in [start, end) is in some region.
+    fn is_covered(&self, start: usize, end: usize) -> bool {
+        let mut covered = start;
+        for vma in self.vmas.iter().filter(|vma| vma.overlaps(start, end)) {
+            if vma.start > covered {
+                return false;
+            }
+            covered = vma.end;
+        }
+        covered >= end
+    }
+
+    fn check_flags(flags: usize) -> Result<(), VmaError> {
+        let read = flags & EntryFlags::Read as usize != 0;
+        let write = flags & EntryFlags::Write as usize != 0;
+        if flags & EntryFlags::User as usize == 0 || (write && !read) {
+            return Err(VmaError::InvalidFlags);
+        }
+        Ok(())
+    }
+
+    fn check_range(start: usize, end: usize) -> Result<(), VmaError> {
+        if start >= end
+            || end > arch::USER_END
+            || !start.is_multiple_of(PAGE_SIZE)
+            || !end.is_multiple_of(PAGE_SIZE)
+        {
+            return Err(VmaError::InvalidRange);
+        }
+        Ok(())
+    }
+}
+
+impl Default for AddressSpace {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        for vma in core::mem::take(&mut self.vmas) {
+            self.release_pages(&vma);
+        }
+        self.page_table
+            .free_process_tables(&crate::ROOT_PAGE_TABLE.lock());
+    }
+}
+
+/// A zeroed page for user memory, by its physical address.
+fn alloc_frame() -> Option<u64> {
+    let frame = PAGE_ALLOCATOR.lock().zero_alloc(1);
+    (!frame.is_null()).then(|| arch::virt_to_phys(frame as usize) as u64)
+}
+
+fn free_frame(frame: u64) {
+    PAGE_ALLOCATOR
+        .lock()
+        .dealloc(arch::phys_to_virt(frame as usize) as *mut u8);
+}
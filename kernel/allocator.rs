@@ -1,20 +1,122 @@
 use crate::arch::PAGE_ORDER;
 use crate::arch::PAGE_SIZE;
-use core::alloc::GlobalAlloc;
+use crate::spinlock::IrqSpinLock;
+use core::alloc::{GlobalAlloc, Layout};
 
 use crate::page::{self};
 
 extern crate alloc;
 
+/// Object sizes the slab allocator hands out. Anything bigger gets whole pages.
+/// All powers of two, so objects carved out of a page at multiples of their size are aligned to their size.
+const SIZE_CLASSES: [usize; 8] = [16, 32, 64, 128, 256, 512, 1024, 2048];
+
+static SLABS: IrqSpinLock<SlabAllocator> = IrqSpinLock::new(SlabAllocator::new());
+
 pub struct KernelAllocator;
 
+/// Returns the index of the smallest size class that fits `layout`, size and alignment both.
+fn size_class(layout: &Layout) -> Option<usize> {
+    let size = layout.size().max(layout.align());
+    SIZE_CLASSES.iter().position(|&class| class >= size)
+}
+
+fn nr_of_pages(layout: &Layout) -> usize {
+    page::align_val(layout.size(), PAGE_ORDER) / PAGE_SIZE
+}
+
 unsafe impl GlobalAlloc for KernelAllocator {
-    unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
-        let nr_of_pages = page::align_val(layout.size(), PAGE_ORDER) / PAGE_SIZE;
-        page::PAGE_ALLOCATOR.lock().alloc(nr_of_pages)
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        match size_class(&layout) {
+            Some(class) => SLABS.lock().alloc(class),
+            None => {
+                // Pages are always page aligned, bigger alignments have to be asked for
+                let align_pages = (layout.align() / PAGE_SIZE).max(1);
+                page::PAGE_ALLOCATOR
+                    .lock()
+                    .alloc_aligned(nr_of_pages(&layout), align_pages)
+            }
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        match size_class(&layout) {
+            Some(class) => SLABS.lock().dealloc(class, ptr),
+            None => page::PAGE_ALLOCATOR.lock().dealloc(ptr),
+        }
+    }
+
+    /// Stays in place when the new size still fits the same size class or the same pages.
+    /// Shrinking page allocations keeps the tail pages, which are freed together with the rest later on.
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = unsafe { Layout::from_size_align_unchecked(new_size, layout.align()) };
+        match (size_class(&layout), size_class(&new_layout)) {
+            (Some(old), Some(new)) if old == new => return ptr,
+            (None, None) if nr_of_pages(&new_layout) <= nr_of_pages(&layout) => return ptr,
+            _ => {}
+        }
+
+        let new_ptr = unsafe { self.alloc(new_layout) };
+        if !new_ptr.is_null() {
+            unsafe {
+                core::ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+                self.dealloc(ptr, layout);
+            }
+        }
+        new_ptr
+    }
+}
+
+/// Links the free objects of a size class together, stored in the objects themselves.
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+/// Per size class free lists, refilled one page at a time from the page allocator.
+/// Pages are never given back, a page that once held objects of a class keeps doing so.
+struct SlabAllocator {
+    free_lists: [*mut FreeObject; SIZE_CLASSES.len()],
+}
+
+//Safety: The free lists only point to memory owned by the allocator, which is only reached through the lock
+unsafe impl Send for SlabAllocator {}
+
+impl SlabAllocator {
+    const fn new() -> Self {
+        SlabAllocator {
+            free_lists: [core::ptr::null_mut(); SIZE_CLASSES.len()],
+        }
+    }
+
+    fn alloc(&mut self, class: usize) -> *mut u8 {
+        if self.free_lists[class].is_null() && !self.refill(class) {
+            return core::ptr::null_mut();
+        }
+        let object = self.free_lists[class];
+        unsafe {
+            self.free_lists[class] = (*object).next;
+        }
+        object as *mut u8
+    }
+
+    fn dealloc(&mut self, class: usize, ptr: *mut u8) {
+        let object = ptr as *mut FreeObject;
+        unsafe {
+            (*object).next = self.free_lists[class];
+        }
+        self.free_lists[class] = object;
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, _layout: core::alloc::Layout) {
-        page::PAGE_ALLOCATOR.lock().dealloc(ptr);
+    /// Carves a fresh page into objects of the given class. Returns false when out of pages.
+    fn refill(&mut self, class: usize) -> bool {
+        let page = page::PAGE_ALLOCATOR.lock().alloc(1);
+        if page.is_null() {
+            return false;
+        }
+        let size = SIZE_CLASSES[class];
+        for offset in (0..PAGE_SIZE).step_by(size) {
+            self.dealloc(class, unsafe { page.add(offset) });
+        }
+        true
     }
 }
@@ -0,0 +1,31 @@
+use super::layout::{DIRECT_MAP_BASE, DIRECT_MAP_PHYS, DIRECT_MAP_SIZE};
+use crate::page_table::{EntryFlags, MEGAPAGE_SIZE};
+
+/// Root table for harts coming up with paging off, until they switch to the kernel table. It maps the whole
+/// direct map twice with megapages: where the kernel is linked and at the physical addresses, for the
+/// instructions that turn paging on and jump up.
+#[repr(C, align(4096))]
+pub struct BootPageTable([usize; 1024]);
+
+/// Used by the boot hart in `kernel_boot` and by secondary harts in `__secondary_entry`.
+/// Both take its address with `la` while running at physical addresses, which gives the physical one.
+pub static BOOT_PAGE_TABLE: BootPageTable = BootPageTable::new();
+
+impl BootPageTable {
+    const fn new() -> Self {
+        let flags = EntryFlags::Valid as usize
+            | EntryFlags::Read as usize
+            | EntryFlags::Write as usize
+            | EntryFlags::Execute as usize;
+        let mut entries = [0; 1024];
+        let mut offset = 0;
+        while offset < DIRECT_MAP_SIZE {
+            let phys_address = DIRECT_MAP_PHYS + offset;
+            let entry = (phys_address >> 12) << 10 | flags;
+            entries[phys_address / MEGAPAGE_SIZE] = entry;
+            entries[(DIRECT_MAP_BASE + offset) / MEGAPAGE_SIZE] = entry;
+            offset += MEGAPAGE_SIZE;
+        }
+        BootPageTable(entries)
+    }
+}
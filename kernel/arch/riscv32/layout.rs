@@ -0,0 +1,46 @@
+// How the 4 GiB of Sv32 virtual addresses are split up:
+//
+//   0x0000_0000..0x8000_0000  user space, different in every process
+//   0x8000_0000..0xc000_0000  device registers, handed out by `map_device`
+//   0xc000_0000..             the direct map: all RAM, starting at physical 0x8000_0000 (QEMU virt)
+//
+// The kernel image is linked inside the direct map, at the address its load address maps to.
+// Keep boot/riscv32/script.ld in sync.
+
+/// End of user space, the kernel half starts here.
+pub const USER_END: usize = 0x8000_0000;
+pub const MMIO_BASE: usize = 0x8000_0000;
+pub const MMIO_END: usize = DIRECT_MAP_BASE;
+pub const DIRECT_MAP_BASE: usize = 0xc000_0000;
+/// Physical address of the first byte of the direct map.
+pub const DIRECT_MAP_PHYS: usize = 0x8000_0000;
+/// RAM past this much is out of reach of the kernel.
+pub const DIRECT_MAP_SIZE: usize = 0usize.wrapping_sub(DIRECT_MAP_BASE);
+/// What to add to a physical address in RAM to get the address the kernel reaches it at.
+pub const DIRECT_MAP_OFFSET: usize = DIRECT_MAP_BASE - DIRECT_MAP_PHYS;
+
+/// Whether the physical address `phys` is in the direct map.
+pub fn is_direct_mapped(phys: usize) -> bool {
+    (DIRECT_MAP_PHYS..DIRECT_MAP_PHYS + DIRECT_MAP_SIZE).contains(&phys)
+}
+
+/// The address the kernel reaches the physical address `phys` at.
+pub fn phys_to_virt(phys: usize) -> usize {
+    debug_assert!(
+        is_direct_mapped(phys),
+        "{:#x} is not in the direct map",
+        phys
+    );
+    phys + DIRECT_MAP_OFFSET
+}
+
+/// The physical address behind the kernel address `virt`, which has to be in the direct map.
+/// That is all of the kernel image, the kernel heap and every page from the page allocator.
+pub fn virt_to_phys(virt: usize) -> usize {
+    debug_assert!(
+        virt >= DIRECT_MAP_BASE,
+        "{:#x} is not in the direct map",
+        virt
+    );
+    virt - DIRECT_MAP_OFFSET
+}
@@ -1,12 +1,14 @@
 use crate::arch::PAGE_ORDER;
 use core::arch::global_asm;
 
+#[derive(Clone, Copy)]
 pub struct Satp {
     value: usize,
 }
 impl Satp {
-    pub const fn new(ppn: usize) -> Self {
-        let value = 1usize << 31 | (ppn >> PAGE_ORDER);
+    /// `root_table` is the physical address of the root page table, see `virt_to_phys`.
+    pub const fn new(root_table: usize) -> Self {
+        let value = 1usize << 31 | (root_table >> PAGE_ORDER);
         Satp { value }
     }
 
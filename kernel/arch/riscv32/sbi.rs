@@ -1,27 +1,154 @@
+//! Client for the RISC-V Supervisor Binary Interface, through which we ask the firmware (OpenSBI) for
+//! timers, IPIs, remote fences, hart management, resets and console access.
+//! See https://github.com/riscv-non-isa/riscv-sbi-doc for the calls.
+use crate::info;
 use core::arch::asm;
+use core::fmt::Display;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 #[derive(Default)]
 struct SbiArgs {
-    arg0: u32,
-    arg1: u32,
-    arg2: u32,
-    arg3: u32,
-    arg4: u32,
-    arg5: u32,
-    fid: u32,
-    eid: u32,
+    arg0: usize,
+    arg1: usize,
+    arg2: usize,
+    arg3: usize,
+    arg4: usize,
+    arg5: usize,
+    fid: usize,
+    eid: usize,
 }
 
-/// Not used at the moment
-#[allow(dead_code)]
-struct SbiResult {
-    error: u32,
-    value: u32,
+/// The standard SBI error codes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SbiError {
+    Failed,
+    NotSupported,
+    InvalidParam,
+    Denied,
+    InvalidAddress,
+    AlreadyAvailable,
+    AlreadyStarted,
+    AlreadyStopped,
+    NoSharedMemory,
+    InvalidState,
+    BadRange,
+    Timeout,
+    Io,
+    Unknown(isize),
 }
 
+impl SbiError {
+    fn from_code(code: isize) -> Self {
+        match code {
+            -1 => SbiError::Failed,
+            -2 => SbiError::NotSupported,
+            -3 => SbiError::InvalidParam,
+            -4 => SbiError::Denied,
+            -5 => SbiError::InvalidAddress,
+            -6 => SbiError::AlreadyAvailable,
+            -7 => SbiError::AlreadyStarted,
+            -8 => SbiError::AlreadyStopped,
+            -9 => SbiError::NoSharedMemory,
+            -10 => SbiError::InvalidState,
+            -11 => SbiError::BadRange,
+            -12 => SbiError::Timeout,
+            -13 => SbiError::Io,
+            code => SbiError::Unknown(code),
+        }
+    }
+}
+
+pub type SbiResult<T> = Result<T, SbiError>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(usize)]
+pub enum Extension {
+    LegacySetTimer = 0x00,
+    LegacyConsolePutchar = 0x01,
+    Base = 0x10,
+    Time = 0x54494D45,
+    Ipi = 0x735049,
+    Rfence = 0x52464E43,
+    Hsm = 0x48534D,
+    Srst = 0x53525354,
+    Dbcn = 0x4442434E,
+}
+
+impl Extension {
+    pub const ALL: [Extension; 9] = [
+        Extension::LegacySetTimer,
+        Extension::LegacyConsolePutchar,
+        Extension::Base,
+        Extension::Time,
+        Extension::Ipi,
+        Extension::Rfence,
+        Extension::Hsm,
+        Extension::Srst,
+        Extension::Dbcn,
+    ];
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(usize)]
+pub enum ResetType {
+    Shutdown = 0,
+    ColdReboot = 1,
+    WarmReboot = 2,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(usize)]
+pub enum ResetReason {
+    None = 0,
+    SystemFailure = 1,
+}
+
+/// State of a hart as reported by the HSM extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HartState {
+    Started,
+    Stopped,
+    StartPending,
+    StopPending,
+    Suspended,
+    SuspendPending,
+    ResumePending,
+    Unknown(usize),
+}
+
+impl HartState {
+    fn from_value(value: usize) -> Self {
+        match value {
+            0 => HartState::Started,
+            1 => HartState::Stopped,
+            2 => HartState::StartPending,
+            3 => HartState::StopPending,
+            4 => HartState::Suspended,
+            5 => HartState::SuspendPending,
+            6 => HartState::ResumePending,
+            value => HartState::Unknown(value),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpecVersion {
+    pub major: usize,
+    pub minor: usize,
+}
+
+impl Display for SpecVersion {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+/// Set by `init` when the firmware has the debug console, so we don't have to probe on every write.
+static HAS_DBCN: AtomicBool = AtomicBool::new(false);
+
 pub struct Sbi;
 impl Sbi {
-    fn call(args: &SbiArgs) -> SbiResult {
+    fn call(args: &SbiArgs) -> SbiResult<usize> {
         let mut a0 = args.arg0;
         let mut a1 = args.arg1;
 
@@ -42,19 +169,301 @@ impl Sbi {
             );
         }
 
-        SbiResult {
-            error: a0,
-            value: a1,
+        match a0 as isize {
+            0 => Ok(a1),
+            error => Err(SbiError::from_code(error)),
         }
     }
 
+    /// Legacy version of `set_timer`, for firmware without the TIME extension.
+    pub fn legacy_set_timer(stime_value: u64) {
+        let args = SbiArgs {
+            arg0: stime_value as usize,
+            arg1: (stime_value >> 32) as usize,
+            fid: 0,
+            eid: Extension::LegacySetTimer as usize,
+            ..Default::default()
+        };
+        // Legacy calls don't return an error code
+        let _ = Sbi::call(&args);
+    }
+
+    /// Legacy console output. Deprecated, but the only output every firmware has.
     pub fn put_char(to_write: char) {
         let args = SbiArgs {
-            arg0: to_write as u32,
+            arg0: to_write as usize,
             fid: 0,
-            eid: 1,
+            eid: Extension::LegacyConsolePutchar as usize,
             ..Default::default()
         };
+        // Legacy calls don't return an error code
         let _ = Sbi::call(&args);
     }
+
+    // Base extension
+
+    pub fn spec_version() -> SbiResult<SpecVersion> {
+        let version = Sbi::base_call(0)?;
+        Ok(SpecVersion {
+            major: (version >> 24) & 0x7f,
+            minor: version & 0xff_ffff,
+        })
+    }
+
+    pub fn impl_id() -> SbiResult<usize> {
+        Sbi::base_call(1)
+    }
+
+    pub fn impl_version() -> SbiResult<usize> {
+        Sbi::base_call(2)
+    }
+
+    /// Whether the firmware implements the extension.
+    pub fn probe_extension(extension: Extension) -> bool {
+        let args = SbiArgs {
+            arg0: extension as usize,
+            fid: 3,
+            eid: Extension::Base as usize,
+            ..Default::default()
+        };
+        Sbi::call(&args).is_ok_and(|available| available != 0)
+    }
+
+    fn base_call(fid: usize) -> SbiResult<usize> {
+        let args = SbiArgs {
+            fid,
+            eid: Extension::Base as usize,
+            ..Default::default()
+        };
+        Sbi::call(&args)
+    }
+
+    // TIME extension
+
+    /// Programs the timer for the next event at `stime_value` (in `time` CSR ticks).
+    pub fn set_timer(stime_value: u64) -> SbiResult<()> {
+        let args = SbiArgs {
+            arg0: stime_value as usize,
+            arg1: (stime_value >> 32) as usize,
+            fid: 0,
+            eid: Extension::Time as usize,
+            ..Default::default()
+        };
+        Sbi::call(&args).map(|_| ())
+    }
+
+    // IPI extension
+
+    /// Sends a supervisor software interrupt to the harts in `hart_mask`, counted from `hart_mask_base`.
+    pub fn send_ipi(hart_mask: usize, hart_mask_base: usize) -> SbiResult<()> {
+        let args = SbiArgs {
+            arg0: hart_mask,
+            arg1: hart_mask_base,
+            fid: 0,
+            eid: Extension::Ipi as usize,
+            ..Default::default()
+        };
+        Sbi::call(&args).map(|_| ())
+    }
+
+    // RFENCE extension
+
+    pub fn remote_fence_i(hart_mask: usize, hart_mask_base: usize) -> SbiResult<()> {
+        let args = SbiArgs {
+            arg0: hart_mask,
+            arg1: hart_mask_base,
+            fid: 0,
+            eid: Extension::Rfence as usize,
+            ..Default::default()
+        };
+        Sbi::call(&args).map(|_| ())
+    }
+
+    /// Runs `sfence.vma` for `start..start + size` on the given harts. A size of `usize::MAX` flushes everything.
+    pub fn remote_sfence_vma(
+        hart_mask: usize,
+        hart_mask_base: usize,
+        start: usize,
+        size: usize,
+    ) -> SbiResult<()> {
+        let args = SbiArgs {
+            arg0: hart_mask,
+            arg1: hart_mask_base,
+            arg2: start,
+            arg3: size,
+            fid: 1,
+            eid: Extension::Rfence as usize,
+            ..Default::default()
+        };
+        Sbi::call(&args).map(|_| ())
+    }
+
+    pub fn remote_sfence_vma_asid(
+        hart_mask: usize,
+        hart_mask_base: usize,
+        start: usize,
+        size: usize,
+        asid: usize,
+    ) -> SbiResult<()> {
+        let args = SbiArgs {
+            arg0: hart_mask,
+            arg1: hart_mask_base,
+            arg2: start,
+            arg3: size,
+            arg4: asid,
+            fid: 2,
+            eid: Extension::Rfence as usize,
+            ..Default::default()
+        };
+        Sbi::call(&args).map(|_| ())
+    }
+
+    // HSM extension
+
+    /// Starts `hart_id` in supervisor mode at the physical address `start_address`, with `a0` = hart id and `a1` = `opaque`.
+    pub fn hart_start(hart_id: usize, start_address: usize, opaque: usize) -> SbiResult<()> {
+        let args = SbiArgs {
+            arg0: hart_id,
+            arg1: start_address,
+            arg2: opaque,
+            fid: 0,
+            eid: Extension::Hsm as usize,
+            ..Default::default()
+        };
+        Sbi::call(&args).map(|_| ())
+    }
+
+    /// Stops the calling hart. Only returns on failure.
+    pub fn hart_stop() -> SbiError {
+        let args = SbiArgs {
+            fid: 1,
+            eid: Extension::Hsm as usize,
+            ..Default::default()
+        };
+        Sbi::call(&args).err().unwrap_or(SbiError::Failed)
+    }
+
+    pub fn hart_status(hart_id: usize) -> SbiResult<HartState> {
+        let args = SbiArgs {
+            arg0: hart_id,
+            fid: 2,
+            eid: Extension::Hsm as usize,
+            ..Default::default()
+        };
+        Sbi::call(&args).map(HartState::from_value)
+    }
+
+    // SRST extension
+
+    /// Shuts down or reboots the machine. Only returns on failure.
+    pub fn system_reset(reset_type: ResetType, reason: ResetReason) -> SbiError {
+        let args = SbiArgs {
+            arg0: reset_type as usize,
+            arg1: reason as usize,
+            fid: 0,
+            eid: Extension::Srst as usize,
+            ..Default::default()
+        };
+        Sbi::call(&args).err().unwrap_or(SbiError::Failed)
+    }
+
+    // DBCN extension
+
+    /// Writes as much of `bytes` as the firmware takes at once, returning how much that was.
+    /// `bytes` has to be in the direct map, since the firmware reads it by physical address.
+    pub fn debug_console_write(bytes: &[u8]) -> SbiResult<usize> {
+        let args = SbiArgs {
+            arg0: bytes.len(),
+            arg1: super::virt_to_phys(bytes.as_ptr() as usize),
+            arg2: 0,
+            fid: 0,
+            eid: Extension::Dbcn as usize,
+            ..Default::default()
+        };
+        Sbi::call(&args)
+    }
+
+    /// Reads whatever input is pending into `buffer`, returning how many bytes that was.
+    /// Like for `debug_console_write`, `buffer` has to be in the direct map.
+    pub fn debug_console_read(buffer: &mut [u8]) -> SbiResult<usize> {
+        let args = SbiArgs {
+            arg0: buffer.len(),
+            arg1: super::virt_to_phys(buffer.as_mut_ptr() as usize),
+            arg2: 0,
+            fid: 1,
+            eid: Extension::Dbcn as usize,
+            ..Default::default()
+        };
+        Sbi::call(&args)
+    }
+}
+
+/// Console output through the firmware: the debug console if there is one, the legacy call otherwise.
+pub fn console_write(s: &str) {
+    let mut bytes = s.as_bytes();
+    if HAS_DBCN.load(Ordering::Relaxed) {
+        while !bytes.is_empty() {
+            match Sbi::debug_console_write(bytes) {
+                // Nothing taken means the console is busy, don't spin on it
+                Ok(0) | Err(_) => break,
+                Ok(written) => bytes = &bytes[written.min(bytes.len())..],
+            }
+        }
+    }
+    // Whatever the debug console did not take goes out the old way
+    for &byte in bytes {
+        Sbi::put_char(byte as char);
+    }
+}
+
+/// Console input through the firmware's debug console, if it has one.
+pub fn console_read(buffer: &mut [u8]) -> usize {
+    if !HAS_DBCN.load(Ordering::Relaxed) {
+        return 0;
+    }
+    Sbi::debug_console_read(buffer).unwrap_or(0)
+}
+
+fn impl_name(impl_id: usize) -> &'static str {
+    match impl_id {
+        0 => "BBL",
+        1 => "OpenSBI",
+        2 => "Xvisor",
+        3 => "KVM",
+        4 => "RustSBI",
+        5 => "Diosix",
+        6 => "Coffer",
+        7 => "Xen",
+        8 => "PolarFire HSS",
+        9 => "coreboot",
+        10 => "oreboot",
+        11 => "bhyve",
+        _ => "unknown",
+    }
+}
+
+/// Finds out what the firmware supports and logs it.
+pub fn init() {
+    match (Sbi::spec_version(), Sbi::impl_id(), Sbi::impl_version()) {
+        (Ok(spec), Ok(impl_id), Ok(impl_version)) => info!(
+            "SBI v{}, implementation {} ({}) version {:#x}",
+            spec,
+            impl_name(impl_id),
+            impl_id,
+            impl_version
+        ),
+        // The base extension is mandatory since v0.2
+        _ => info!("SBI v0.1 firmware"),
+    }
+    HAS_DBCN.store(Sbi::probe_extension(Extension::Dbcn), Ordering::Relaxed);
+    for extension in Extension::ALL {
+        info!(
+            "SBI extension {:?}: {}",
+            extension,
+            match Sbi::probe_extension(extension) {
+                true => "available",
+                false => "missing",
+            }
+        );
+    }
 }
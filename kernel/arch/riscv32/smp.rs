@@ -0,0 +1,104 @@
+use super::sbi::{Sbi, SbiResult};
+use super::{virt_to_phys, Satp, BOOT_PAGE_TABLE, DIRECT_MAP_OFFSET};
+use core::arch::{asm, global_asm};
+use core::mem::offset_of;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// What a secondary hart needs before it can run kernel code, read by `__secondary_entry`.
+/// It has to outlive the start, since the hart reads it only once it is running.
+pub struct HartStart {
+    stack_top: AtomicUsize,
+    satp: AtomicUsize,
+    thread_pointer: AtomicUsize,
+    entry: AtomicUsize,
+}
+
+impl HartStart {
+    pub const fn new() -> Self {
+        HartStart {
+            stack_top: AtomicUsize::new(0),
+            satp: AtomicUsize::new(0),
+            thread_pointer: AtomicUsize::new(0),
+            entry: AtomicUsize::new(0),
+        }
+    }
+
+    /// Starts `hart_id` at `entry`, on `stack_top`, with paging on through `satp` and `tp` set to `thread_pointer`.
+    pub fn start(
+        &'static self,
+        hart_id: usize,
+        stack_top: usize,
+        satp: &Satp,
+        thread_pointer: usize,
+        entry: extern "C" fn() -> !,
+    ) -> SbiResult<()> {
+        self.stack_top.store(stack_top, Ordering::Relaxed);
+        self.satp.store(satp.get(), Ordering::Relaxed);
+        self.thread_pointer.store(thread_pointer, Ordering::Relaxed);
+        self.entry.store(entry as usize, Ordering::Release);
+        // The hart starts with paging off, so it needs the physical addresses
+        Sbi::hart_start(
+            hart_id,
+            virt_to_phys(__secondary_entry as *const () as usize),
+            virt_to_phys(self as *const _ as usize),
+        )
+    }
+}
+
+impl Default for HartStart {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Points `tp` at the per-CPU data of this hart.
+pub fn set_thread_pointer(value: usize) {
+    unsafe {
+        asm!("mv tp, {}", in(reg) value);
+    }
+}
+
+pub fn thread_pointer() -> usize {
+    let value: usize;
+    unsafe {
+        asm!("mv {}, tp", out(reg) value);
+    }
+    value
+}
+
+extern "C" {
+    fn __secondary_entry();
+}
+
+// Where the firmware starts a secondary hart: a0 is its hart id, a1 the physical address of its `HartStart`.
+// Paging is off, so this runs from the physical address of the kernel text, which the kernel table does not
+// map. Like `kernel_boot` it goes through `BOOT_PAGE_TABLE`, which maps that too, to jump up into the direct
+// map, and only then switches to the kernel table.
+global_asm!(
+    "__secondary_entry:",
+    "lw sp, {stack_top}(a1)",
+    "lw tp, {thread_pointer}(a1)",
+    "lw t0, {satp}(a1)",
+    "lw t1, {entry}(a1)",
+    // `la` is relative to the pc, so until the jump it gives physical addresses
+    "la t2, {boot_table}",
+    "srli t2, t2, 12",
+    "li t3, 1 << 31",
+    "or t2, t2, t3",
+    "csrw satp, t2",
+    "sfence.vma",
+    "la t2, 1f",
+    "li t3, {offset}",
+    "add t2, t2, t3",
+    "jr t2",
+    "1:",
+    "csrw satp, t0",
+    "sfence.vma",
+    "jr t1",
+    boot_table = sym BOOT_PAGE_TABLE,
+    offset = const DIRECT_MAP_OFFSET,
+    stack_top = const offset_of!(HartStart, stack_top),
+    thread_pointer = const offset_of!(HartStart, thread_pointer),
+    satp = const offset_of!(HartStart, satp),
+    entry = const offset_of!(HartStart, entry),
+);
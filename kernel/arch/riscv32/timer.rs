@@ -0,0 +1,50 @@
+use super::sbi::Sbi;
+use core::arch::asm;
+
+/// Frequency of the `time` CSR on QEMU's virt machine.
+pub const TIMEBASE_FREQUENCY: u64 = 10_000_000;
+
+/// Supervisor timer interrupt enable bit in `sie`.
+const SIE_STIE: usize = 1 << 5;
+
+/// Reads the 64-bit `time` CSR.
+///
+/// On riscv32 the counter is split over `time` and `timeh`, so we retry if the upper half
+/// changed while we were reading the lower half.
+pub fn read_time() -> u64 {
+    loop {
+        let high: u32;
+        let low: u32;
+        let high_check: u32;
+        unsafe {
+            asm!(
+                "rdtimeh {0}",
+                "rdtime {1}",
+                "rdtimeh {2}",
+                out(reg) high,
+                out(reg) low,
+                out(reg) high_check,
+            );
+        }
+        if high == high_check {
+            return ((high as u64) << 32) | low as u64;
+        }
+    }
+}
+
+/// Programs the next timer interrupt `ms` milliseconds from now.
+/// This also clears any pending timer interrupt.
+pub fn set_timer_in(ms: usize) {
+    let ticks = TIMEBASE_FREQUENCY / 1000 * ms as u64;
+    let deadline = read_time() + ticks;
+    // Called from the timer trap, so fall back instead of failing on old firmware
+    if Sbi::set_timer(deadline).is_err() {
+        Sbi::legacy_set_timer(deadline);
+    }
+}
+
+pub fn enable_timer_interrupts() {
+    unsafe {
+        asm!("csrs sie, {}", in(reg) SIE_STIE);
+    }
+}
@@ -0,0 +1,422 @@
+use core::arch::asm;
+use core::fmt::{self, Display};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+const SCAUSE_INTERRUPT: usize = 1 << 31;
+/// Previous privilege, set when the trap came from supervisor mode.
+const SSTATUS_SPP: usize = 1 << 8;
+/// Both interrupt and exception codes of the privileged spec fit in 4 bits.
+const CAUSE_CODES: usize = 16;
+
+/// Interrupt codes as found in `scause` when the interrupt bit is set.
+#[repr(usize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Interrupt {
+    SupervisorSoftware = 1,
+    SupervisorTimer = 5,
+    SupervisorExternal = 9,
+}
+
+/// Exception codes as found in `scause` when the interrupt bit is clear.
+#[repr(usize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Exception {
+    InstructionAddressMisaligned = 0,
+    InstructionAccessFault = 1,
+    IllegalInstruction = 2,
+    Breakpoint = 3,
+    LoadAddressMisaligned = 4,
+    LoadAccessFault = 5,
+    StoreAddressMisaligned = 6,
+    StoreAccessFault = 7,
+    EnvironmentCallFromUMode = 8,
+    EnvironmentCallFromSMode = 9,
+    InstructionPageFault = 12,
+    LoadPageFault = 13,
+    StorePageFault = 15,
+}
+
+/// Decoded `scause`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TrapCause {
+    Interrupt(Interrupt),
+    Exception(Exception),
+    /// A reserved or platform specific code we do not know about.
+    Unknown(usize),
+}
+
+impl TrapCause {
+    pub fn from_scause(scause: usize) -> Self {
+        let code = scause & !SCAUSE_INTERRUPT;
+        if scause & SCAUSE_INTERRUPT != 0 {
+            let interrupt = match code {
+                1 => Interrupt::SupervisorSoftware,
+                5 => Interrupt::SupervisorTimer,
+                9 => Interrupt::SupervisorExternal,
+                _ => return TrapCause::Unknown(scause),
+            };
+            return TrapCause::Interrupt(interrupt);
+        }
+        let exception = match code {
+            0 => Exception::InstructionAddressMisaligned,
+            1 => Exception::InstructionAccessFault,
+            2 => Exception::IllegalInstruction,
+            3 => Exception::Breakpoint,
+            4 => Exception::LoadAddressMisaligned,
+            5 => Exception::LoadAccessFault,
+            6 => Exception::StoreAddressMisaligned,
+            7 => Exception::StoreAccessFault,
+            8 => Exception::EnvironmentCallFromUMode,
+            9 => Exception::EnvironmentCallFromSMode,
+            12 => Exception::InstructionPageFault,
+            13 => Exception::LoadPageFault,
+            15 => Exception::StorePageFault,
+            _ => return TrapCause::Unknown(scause),
+        };
+        TrapCause::Exception(exception)
+    }
+
+    pub fn is_interrupt(&self) -> bool {
+        matches!(self, TrapCause::Interrupt(_))
+    }
+
+    /// Slot of this cause in the handler table. Interrupts come after the exceptions.
+    fn index(&self) -> Option<usize> {
+        match self {
+            TrapCause::Exception(e) => Some(*e as usize),
+            TrapCause::Interrupt(i) => Some(CAUSE_CODES + *i as usize),
+            TrapCause::Unknown(_) => None,
+        }
+    }
+}
+
+impl Display for TrapCause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrapCause::Interrupt(i) => write!(f, "{:?} interrupt", i),
+            TrapCause::Exception(e) => write!(f, "{:?} exception", e),
+            TrapCause::Unknown(scause) => write!(f, "unknown trap (scause={:#x})", scause),
+        }
+    }
+}
+
+/// Called with the saved state of the trapped code and the faulting address or instruction from `stval`.
+/// Changes made to the frame (e.g. `sepc` or `a0`) are restored when the trap returns.
+pub type TrapHandler = fn(frame: &mut TrapFrame, stval: usize);
+
+/// Registered handlers, stored as function pointers so the trap path never has to take a lock.
+static HANDLERS: [AtomicUsize; 2 * CAUSE_CODES] = [const { AtomicUsize::new(0) }; 2 * CAUSE_CODES];
+
+/// Installs the handler for the given cause.
+/// Each cause has a single owner, so registering twice is a bug.
+pub fn register_trap_handler(cause: TrapCause, handler: TrapHandler) {
+    let index = cause
+        .index()
+        .unwrap_or_else(|| panic!("Cannot register a handler for {}", cause));
+    let previous = HANDLERS[index].swap(handler as usize, Ordering::AcqRel);
+    assert!(
+        previous == 0,
+        "A handler for {} was already registered",
+        cause
+    );
+}
+
+/// How often each cause was taken, indexed like `HANDLERS`.
+static COUNTS: [AtomicUsize; 2 * CAUSE_CODES] = [const { AtomicUsize::new(0) }; 2 * CAUSE_CODES];
+
+/// Every known cause that was taken at least once, with how often.
+pub fn trap_counts() -> impl Iterator<Item = (TrapCause, usize)> {
+    (0..CAUSE_CODES)
+        .flat_map(|code| [code | SCAUSE_INTERRUPT, code])
+        .map(TrapCause::from_scause)
+        .filter_map(|cause| Some((cause, COUNTS[cause.index()?].load(Ordering::Relaxed))))
+        .filter(|&(_, count)| count > 0)
+}
+
+fn registered_handler(cause: TrapCause) -> Option<TrapHandler> {
+    let handler = HANDLERS[cause.index()?].load(Ordering::Acquire);
+    if handler == 0 {
+        return None;
+    }
+    //Safety: Only valid TrapHandlers are ever stored in the table
+    Some(unsafe { core::mem::transmute::<usize, TrapHandler>(handler) })
+}
+
+#[no_mangle]
+fn handle_trap(frame: &mut TrapFrame) {
+    let scause = read_csr("scause");
+    let stval = read_csr("stval");
+    let cause = TrapCause::from_scause(scause);
+    if let Some(index) = cause.index() {
+        COUNTS[index].fetch_add(1, Ordering::Relaxed);
+    }
+    match registered_handler(cause) {
+        Some(handler) => handler(frame, stval),
+        None => panic!(
+            "Unhandled {} (scause={:#x}, stval={:#x})\n{}",
+            cause, scause, stval, frame
+        ),
+    }
+}
+
+#[repr(C)]
+pub struct TrapFrame {
+    pub ra: usize,
+    pub gp: usize,
+    pub tp: usize,
+    pub t0: usize,
+    pub t1: usize,
+    pub t2: usize,
+    pub t3: usize,
+    pub t4: usize,
+    pub t5: usize,
+    pub t6: usize,
+    pub a0: usize,
+    pub a1: usize,
+    pub a2: usize,
+    pub a3: usize,
+    pub a4: usize,
+    pub a5: usize,
+    pub a6: usize,
+    pub a7: usize,
+    pub s0: usize,
+    pub s1: usize,
+    pub s2: usize,
+    pub s3: usize,
+    pub s4: usize,
+    pub s5: usize,
+    pub s6: usize,
+    pub s7: usize,
+    pub s8: usize,
+    pub s9: usize,
+    pub s10: usize,
+    pub s11: usize,
+    pub sp: usize,
+    pub sepc: usize,
+    pub sstatus: usize,
+}
+
+/// Syscall ABI: the number goes in a7, the arguments in a0..a5 and the result comes back in a0.
+impl TrapFrame {
+    pub fn syscall_number(&self) -> usize {
+        self.a7
+    }
+
+    pub fn syscall_args(&self) -> [usize; 6] {
+        [self.a0, self.a1, self.a2, self.a3, self.a4, self.a5]
+    }
+
+    pub fn set_syscall_result(&mut self, value: usize) {
+        self.a0 = value;
+    }
+
+    /// Whether the trap came from user mode, i.e. `sstatus.SPP` is clear.
+    pub fn from_user(&self) -> bool {
+        self.sstatus & SSTATUS_SPP == 0
+    }
+
+    /// Makes the trap return after the `ecall` instruction instead of executing it again.
+    /// `ecall` has no compressed form, so it is always 4 bytes.
+    pub fn skip_ecall(&mut self) {
+        self.sepc += 4;
+    }
+}
+
+impl Display for TrapFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let registers = [
+            ("ra", self.ra),
+            ("sp", self.sp),
+            ("gp", self.gp),
+            ("tp", self.tp),
+            ("t0", self.t0),
+            ("t1", self.t1),
+            ("t2", self.t2),
+            ("t3", self.t3),
+            ("t4", self.t4),
+            ("t5", self.t5),
+            ("t6", self.t6),
+            ("a0", self.a0),
+            ("a1", self.a1),
+            ("a2", self.a2),
+            ("a3", self.a3),
+            ("a4", self.a4),
+            ("a5", self.a5),
+            ("a6", self.a6),
+            ("a7", self.a7),
+            ("s0", self.s0),
+            ("s1", self.s1),
+            ("s2", self.s2),
+            ("s3", self.s3),
+            ("s4", self.s4),
+            ("s5", self.s5),
+            ("s6", self.s6),
+            ("s7", self.s7),
+            ("s8", self.s8),
+            ("s9", self.s9),
+            ("s10", self.s10),
+            ("s11", self.s11),
+        ];
+        writeln!(f, "sepc={:#010x} sstatus={:#010x}", self.sepc, self.sstatus)?;
+        for (i, (name, value)) in registers.iter().enumerate() {
+            write!(f, "{:>3}={:#010x}", name, value)?;
+            if i % 4 == 3 {
+                writeln!(f)?;
+            } else {
+                write!(f, " ")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn read_csr(reg: &str) -> usize {
+    let value: usize;
+    unsafe {
+        match reg {
+            "scause" => asm!("csrr {0}, scause", out(reg) value),
+            "stval" => asm!("csrr {0}, stval", out(reg) value),
+            "sepc" => asm!("csrr {0}, sepc", out(reg) value),
+            _ => panic!("Unsupported CSR: {}", reg),
+        }
+    }
+    value
+}
+
+#[repr(usize)]
+pub enum StvecMode {
+    Direct = 0,
+}
+
+pub fn write_stvec(addr: usize, mode: StvecMode) {
+    unsafe {
+        asm!("csrw stvec, {}", in(reg) (addr | mode as usize));
+    }
+}
+
+pub fn init_handlers() {
+    // We are in the kernel, see `kernel_entry`. The firmware does not guarantee sscratch to be zero.
+    unsafe {
+        asm!("csrw sscratch, zero");
+    }
+    // Set trap handler
+    write_stvec(kernel_entry as *const () as usize, StvecMode::Direct);
+}
+
+/// Trap handler entry point of our kernel.
+///
+/// Stores the current program state in registers. Calls the actual trap handler and restores the state.
+/// `sepc` and `sstatus` are part of the saved state, since the trap handler may switch to another process
+/// that traps in turn before we return here.
+/// The frame is padded to 36 words to keep the stack 16-byte aligned.
+///
+/// `sscratch` holds the top of the current process' kernel stack while running in user mode, and zero while
+/// running in the kernel. That is how we know which stack to save the frame on. The page table stays the same,
+/// since every process table also maps the kernel.
+/// # Safety
+/// - This function must only be called during the kernel trap phase.
+/// - `handle_trap` must be a valid function symbol with a proper ABI.
+#[no_mangle]
+#[link_section = ".text.kernel_entry"]
+pub extern "C" fn kernel_entry() {
+    unsafe {
+        asm!(
+            "csrrw sp, sscratch, sp",
+            "bnez sp, 1f",
+            // Trapped from the kernel: keep using the current stack
+            "csrr sp, sscratch",
+            "1:",
+            "addi sp, sp, -4 * 36",
+            "sw ra,  4 * 0(sp)",
+            "sw gp,  4 * 1(sp)",
+            "sw tp,  4 * 2(sp)",
+            "sw t0,  4 * 3(sp)",
+            "sw t1,  4 * 4(sp)",
+            "sw t2,  4 * 5(sp)",
+            "sw t3,  4 * 6(sp)",
+            "sw t4,  4 * 7(sp)",
+            "sw t5,  4 * 8(sp)",
+            "sw t6,  4 * 9(sp)",
+            "sw a0,  4 * 10(sp)",
+            "sw a1,  4 * 11(sp)",
+            "sw a2,  4 * 12(sp)",
+            "sw a3,  4 * 13(sp)",
+            "sw a4,  4 * 14(sp)",
+            "sw a5,  4 * 15(sp)",
+            "sw a6,  4 * 16(sp)",
+            "sw a7,  4 * 17(sp)",
+            "sw s0,  4 * 18(sp)",
+            "sw s1,  4 * 19(sp)",
+            "sw s2,  4 * 20(sp)",
+            "sw s3,  4 * 21(sp)",
+            "sw s4,  4 * 22(sp)",
+            "sw s5,  4 * 23(sp)",
+            "sw s6,  4 * 24(sp)",
+            "sw s7,  4 * 25(sp)",
+            "sw s8,  4 * 26(sp)",
+            "sw s9,  4 * 27(sp)",
+            "sw s10, 4 * 28(sp)",
+            "sw s11, 4 * 29(sp)",
+            "csrr a0, sscratch",
+            "sw a0, 4 * 30(sp)",
+            "csrw sscratch, zero",
+            "csrr a0, sepc",
+            "sw a0, 4 * 31(sp)",
+            "csrr a0, sstatus",
+            "sw a0, 4 * 32(sp)",
+            // Trapped from user mode: tp is the user's, take the hart's from above the frame
+            "andi a0, a0, 1 << 8",
+            "bnez a0, 3f",
+            "lw tp, 4 * 36(sp)",
+            "3:",
+            "mv a0, sp",
+            "call {handle_trap}",
+            "lw a0, 4 * 31(sp)",
+            "csrw sepc, a0",
+            "lw a0, 4 * 32(sp)",
+            "csrw sstatus, a0",
+            // Returning to user mode (SPP clear): the next trap lands on top of this kernel stack again,
+            // and finds the tp of whatever hart it is on then right above its frame.
+            // Returning to the kernel keeps tp, the process may have moved to another hart in the meantime.
+            "andi a0, a0, 1 << 8",
+            "bnez a0, 2f",
+            "sw tp, 4 * 36(sp)",
+            "addi a0, sp, 4 * 36",
+            "csrw sscratch, a0",
+            "lw tp,  4 * 2(sp)",
+            "2:",
+            "lw ra,  4 * 0(sp)",
+            "lw gp,  4 * 1(sp)",
+            "lw t0,  4 * 3(sp)",
+            "lw t1,  4 * 4(sp)",
+            "lw t2,  4 * 5(sp)",
+            "lw t3,  4 * 6(sp)",
+            "lw t4,  4 * 7(sp)",
+            "lw t5,  4 * 8(sp)",
+            "lw t6,  4 * 9(sp)",
+            "lw a0,  4 * 10(sp)",
+            "lw a1,  4 * 11(sp)",
+            "lw a2,  4 * 12(sp)",
+            "lw a3,  4 * 13(sp)",
+            "lw a4,  4 * 14(sp)",
+            "lw a5,  4 * 15(sp)",
+            "lw a6,  4 * 16(sp)",
+            "lw a7,  4 * 17(sp)",
+            "lw s0,  4 * 18(sp)",
+            "lw s1,  4 * 19(sp)",
+            "lw s2,  4 * 20(sp)",
+            "lw s3,  4 * 21(sp)",
+            "lw s4,  4 * 22(sp)",
+            "lw s5,  4 * 23(sp)",
+            "lw s6,  4 * 24(sp)",
+            "lw s7,  4 * 25(sp)",
+            "lw s8,  4 * 26(sp)",
+            "lw s9,  4 * 27(sp)",
+            "lw s10, 4 * 28(sp)",
+            "lw s11, 4 * 29(sp)",
+            "lw sp,  4 * 30(sp)",
+            "sret",
+            handle_trap = sym handle_trap,
+        );
+    }
+}
@@ -0,0 +1,37 @@
+use std::{env, path::PathBuf, process::Command};
+
+/// User programs embedded in the kernel image, see `user/bin`.
+const USER_PROGRAMS: [&str; 2] = ["hello", "spin"];
+const USER_TARGET: &str = "riscv32imac-unknown-none-elf";
+
+fn main() {
+    let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let user_dir = manifest_dir.join("../user");
+    let abi_dir = manifest_dir.join("../abi");
+    println!("cargo:rerun-if-changed={}", user_dir.display());
+    println!("cargo:rerun-if-changed={}", abi_dir.display());
+
+    // Build the user programs in their own target dir, the outer cargo holds the lock on ours.
+    let target_dir = PathBuf::from(env::var("OUT_DIR").unwrap()).join("user");
+    let status = Command::new(env::var("CARGO").unwrap())
+        .args(["build", "--release", "--bins", "--target", USER_TARGET])
+        .arg("--manifest-path")
+        .arg(user_dir.join("Cargo.toml"))
+        .arg("--target-dir")
+        .arg(&target_dir)
+        // Don't let flags meant for the kernel (or clippy) leak into the user build
+        .env_remove("CARGO_ENCODED_RUSTFLAGS")
+        .env_remove("RUSTC_WORKSPACE_WRAPPER")
+        .status()
+        .expect("Failed to run cargo for the user programs");
+    assert!(status.success(), "Building the user programs failed");
+
+    for program in USER_PROGRAMS {
+        let elf = target_dir.join(USER_TARGET).join("release").join(program);
+        println!(
+            "cargo:rustc-env=USER_{}_ELF={}",
+            program.to_uppercase(),
+            elf.display()
+        );
+    }
+}
@@ -7,6 +7,14 @@ pub fn print_args(args: Arguments) {
         .expect("Something when wrong writing args");
 }
 
+/// Like `print_args`, but straight through the firmware, without taking the console lock.
+pub fn raw_print_args(args: Arguments) {
+    let mut writer = RawWriter;
+    writer
+        .write_fmt(args)
+        .expect("Something when wrong writing args");
+}
+
 #[macro_export]
 macro_rules! println {
     () => ($crate::print!("\n"));
@@ -26,3 +34,12 @@ impl fmt::Write for Writer {
         Ok(())
     }
 }
+
+struct RawWriter;
+
+impl fmt::Write for RawWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        crate::arch::raw_console_write(s);
+        Ok(())
+    }
+}
@@ -0,0 +1,192 @@
+//! Per-CPU data. Every hart finds its own block through the thread pointer (`tp`).
+use crate::arch::sbi::{Extension, Sbi};
+use crate::{arch, fdt::Fdt, info, page, warn};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+pub const MAX_CPUS: usize = 8;
+/// Kernel stack of a secondary hart, used until it switches to its first process.
+const STACK_PAGES: usize = 4;
+/// How long to wait for a started hart to come online.
+const START_TIMEOUT_MS: u64 = 1000;
+
+static CPUS: [Cpu; MAX_CPUS] = Cpu::all();
+/// How many of `CPUS` are in use, the boot hart being the first.
+static CPU_COUNT: AtomicUsize = AtomicUsize::new(1);
+/// Set by `stop_others`, tells a hart taking an IPI to stop.
+static STOPPING: AtomicBool = AtomicBool::new(false);
+
+/// The processes a hart runs are kept by the scheduler, under `index`.
+pub struct Cpu {
+    index: usize,
+    hart_id: AtomicUsize,
+    online: AtomicBool,
+    start: arch::HartStart,
+    /// How many `IrqSpinLock`s the hart holds, and whether interrupts were on before it took the first.
+    irq_depth: AtomicUsize,
+    irq_enabled: AtomicBool,
+}
+
+impl Cpu {
+    const fn all() -> [Cpu; MAX_CPUS] {
+        let mut cpus = [const { Cpu::new() }; MAX_CPUS];
+        let mut index = 0;
+        while index < MAX_CPUS {
+            cpus[index].index = index;
+            index += 1;
+        }
+        cpus
+    }
+
+    const fn new() -> Self {
+        Cpu {
+            index: 0,
+            hart_id: AtomicUsize::new(0),
+            online: AtomicBool::new(false),
+            start: arch::HartStart::new(),
+            irq_depth: AtomicUsize::new(0),
+            irq_enabled: AtomicBool::new(false),
+        }
+    }
+
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn hart_id(&self) -> usize {
+        self.hart_id.load(Ordering::Relaxed)
+    }
+
+    pub fn is_online(&self) -> bool {
+        self.online.load(Ordering::Acquire)
+    }
+
+    /// Called by the hart itself once it is ready to run processes.
+    pub fn set_online(&self) {
+        self.online.store(true, Ordering::Release);
+    }
+}
+
+/// The per-CPU block of the calling hart. A process can move to another hart
+/// whenever it yields, so don't hold on to this across a switch.
+pub fn current() -> &'static Cpu {
+    unsafe { &*(arch::thread_pointer() as *const Cpu) }
+}
+
+/// The blocks of all harts that were started, online or not.
+pub fn cpus() -> &'static [Cpu] {
+    &CPUS[..CPU_COUNT.load(Ordering::Acquire)]
+}
+
+/// Turns interrupts off on this hart, counting how often, see `pop_interrupts_off`.
+pub fn push_interrupts_off() {
+    // Only once interrupts are off are we sure to stay on this hart
+    let enabled = arch::disable_interrupts();
+    let cpu = current();
+    if cpu.irq_depth.fetch_add(1, Ordering::Relaxed) == 0 {
+        cpu.irq_enabled.store(enabled, Ordering::Relaxed);
+    }
+}
+
+/// Undoes one `push_interrupts_off`. The last one turns interrupts back on, if they were on before the first.
+pub fn pop_interrupts_off() {
+    let cpu = current();
+    let depth = cpu.irq_depth.fetch_sub(1, Ordering::Relaxed);
+    assert!(depth > 0, "pop_interrupts_off without a push");
+    if depth == 1 && cpu.irq_enabled.load(Ordering::Relaxed) {
+        arch::enable_interrupts();
+    }
+}
+
+/// Sets up the block of the boot hart. Must come before anything asks for `current`.
+pub fn init_boot(hart_id: usize) {
+    let cpu = &CPUS[0];
+    cpu.hart_id.store(hart_id, Ordering::Relaxed);
+    cpu.set_online();
+    arch::set_thread_pointer(cpu as *const Cpu as usize);
+    arch::register_trap_handler(
+        arch::TrapCause::Interrupt(arch::Interrupt::SupervisorSoftware),
+        |_, _| handle_ipi(),
+    );
+    arch::enable_software_interrupts();
+}
+
+/// Stops every other online hart, for a panic. Harts spinning with interrupts off only stop once they turn
+/// them on again, but those aren't doing anything else either.
+pub fn stop_others() {
+    // Also when panicking before `init_boot`, when there is no `current` yet
+    if cpus().len() == 1 {
+        return;
+    }
+    STOPPING.store(true, Ordering::Release);
+    let this = current().index();
+    for cpu in cpus()
+        .iter()
+        .filter(|cpu| cpu.index != this && cpu.is_online())
+    {
+        let _ = Sbi::send_ipi(1, cpu.hart_id());
+    }
+}
+
+fn handle_ipi() {
+    arch::clear_software_interrupt();
+    if STOPPING.load(Ordering::Acquire) {
+        current().online.store(false, Ordering::Release);
+        Sbi::hart_stop();
+        // Nothing is left to run here anyway
+        arch::abort();
+    }
+}
+
+/// Starts every other enabled hart in the device tree at `entry`, with paging on through `satp`.
+/// Returns once each of them came online, or gave up on it.
+pub fn start_secondaries(fdt: &Fdt, satp: &arch::Satp, entry: extern "C" fn() -> !) {
+    let boot_hart = current().hart_id();
+    if !Sbi::probe_extension(Extension::Hsm) {
+        warn!("No SBI HSM extension, staying on hart {} alone", boot_hart);
+        return;
+    }
+    let harts = fdt
+        .cpus()
+        .filter(|cpu| cpu.enabled && cpu.hart_id != boot_hart);
+    for hart in harts {
+        let hart_id = hart.hart_id;
+        let index = CPU_COUNT.load(Ordering::Relaxed);
+        if index == MAX_CPUS {
+            warn!(
+                "Only {} harts are supported, leaving hart {} off",
+                MAX_CPUS, hart_id
+            );
+            break;
+        }
+        let stack = page::PAGE_ALLOCATOR.lock().zero_alloc(STACK_PAGES);
+        if stack.is_null() {
+            warn!("No memory for the stack of hart {}", hart_id);
+            break;
+        }
+        let cpu = &CPUS[index];
+        cpu.hart_id.store(hart_id, Ordering::Relaxed);
+        CPU_COUNT.store(index + 1, Ordering::Release);
+
+        let stack_top = stack as usize + STACK_PAGES * arch::PAGE_SIZE;
+        let thread_pointer = cpu as *const Cpu as usize;
+        if let Err(error) = cpu
+            .start
+            .start(hart_id, stack_top, satp, thread_pointer, entry)
+        {
+            warn!("Could not start hart {}: {:?}", hart_id, error);
+            // Nothing ran on it yet, so the block can be used for the next one
+            CPU_COUNT.store(index, Ordering::Release);
+            page::PAGE_ALLOCATOR.lock().dealloc(stack);
+            continue;
+        }
+        let deadline = arch::read_time() + arch::TIMEBASE_FREQUENCY / 1000 * START_TIMEOUT_MS;
+        while !cpu.is_online() && arch::read_time() < deadline {
+            core::hint::spin_loop();
+        }
+        if cpu.is_online() {
+            info!("Hart {} is online as cpu {}", hart_id, index);
+        } else {
+            warn!("Hart {} did not come online", hart_id);
+        }
+    }
+}
@@ -0,0 +1,3 @@
+pub mod plic;
+pub mod syscon;
+pub mod uart;
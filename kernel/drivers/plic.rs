@@ -0,0 +1,200 @@
+//! Driver for the RISC-V Platform-Level Interrupt Controller, which routes device interrupts to harts.
+//! See https://github.com/riscv/riscv-plic-spec for the registers.
+use crate::arch::{self, Interrupt, TrapCause, TrapFrame};
+use crate::cpu::MAX_CPUS;
+use crate::warn;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+pub type IrqHandler = fn(irq: u32);
+
+/// Interrupt sources we keep handlers for. QEMU's virt machine has 95.
+pub const MAX_IRQS: usize = 128;
+const DEFAULT_PRIORITY: u32 = 1;
+
+// Register offsets
+const PRIORITY: usize = 0x0;
+const ENABLE: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+const CONTEXT: usize = 0x20_0000;
+const CONTEXT_STRIDE: usize = 0x1000;
+const THRESHOLD: usize = 0x0;
+const CLAIM: usize = 0x4;
+/// Marks an unused slot of `Plic::contexts`.
+const NO_HART: usize = usize::MAX;
+
+pub static PLIC: Plic = Plic::new();
+
+static HANDLERS: [AtomicUsize; MAX_IRQS] = [const { AtomicUsize::new(0) }; MAX_IRQS];
+
+/// Only holds atomics, so claiming from trap context never waits on a lock.
+pub struct Plic {
+    base: AtomicUsize,
+    nr_sources: AtomicUsize,
+    /// Hart that external interrupts are routed to.
+    hart: AtomicUsize,
+    /// `(hart, context)` pairs from the device tree, harts missing here use `fallback_context`.
+    contexts: [(AtomicUsize, AtomicUsize); MAX_CPUS],
+}
+
+impl Plic {
+    pub const fn new() -> Self {
+        Plic {
+            base: AtomicUsize::new(0),
+            nr_sources: AtomicUsize::new(0),
+            hart: AtomicUsize::new(0),
+            contexts: [const { (AtomicUsize::new(NO_HART), AtomicUsize::new(0)) }; MAX_CPUS],
+        }
+    }
+
+    /// Sets up the PLIC with its registers at `base`, which must be mapped, and routes
+    /// the sources that get a handler to `hart`.
+    pub fn init(&self, base: usize, nr_sources: usize, hart: usize) {
+        self.base.store(base, Ordering::Release);
+        self.nr_sources
+            .store(nr_sources.min(MAX_IRQS - 1), Ordering::Release);
+        self.hart.store(hart, Ordering::Release);
+
+        // Everything stays masked until a driver registers for it
+        for irq in 1..=self.nr_sources() as u32 {
+            self.set_priority(irq, 0);
+            self.disable(hart, irq);
+        }
+        self.set_threshold(hart, 0);
+
+        arch::register_trap_handler(
+            TrapCause::Interrupt(Interrupt::SupervisorExternal),
+            handle_external_interrupt,
+        );
+        arch::enable_external_interrupts();
+    }
+
+    /// Records the S-mode context of `hart`, before `init` so it already uses it.
+    pub fn set_context(&self, hart: usize, context: usize) {
+        let slot = self.contexts.iter().find(|(slot_hart, _)| {
+            let slot_hart = slot_hart.load(Ordering::Acquire);
+            slot_hart == hart || slot_hart == NO_HART
+        });
+        match slot {
+            Some((slot_hart, slot_context)) => {
+                slot_context.store(context, Ordering::Release);
+                slot_hart.store(hart, Ordering::Release);
+            }
+            None => warn!("No room for the PLIC context of hart {}", hart),
+        }
+    }
+
+    /// The S-mode context of a hart, as found in the device tree.
+    fn context(&self, hart: usize) -> usize {
+        self.contexts
+            .iter()
+            .find(|(slot_hart, _)| slot_hart.load(Ordering::Acquire) == hart)
+            .map_or_else(
+                || fallback_context(hart),
+                |(_, context)| context.load(Ordering::Acquire),
+            )
+    }
+
+    pub fn nr_sources(&self) -> usize {
+        self.nr_sources.load(Ordering::Acquire)
+    }
+
+    /// Priority 0 means never interrupt.
+    pub fn set_priority(&self, irq: u32, priority: u32) {
+        self.write_reg(PRIORITY + irq as usize * 4, priority);
+    }
+
+    pub fn enable(&self, hart: usize, irq: u32) {
+        let reg = ENABLE + self.context(hart) * ENABLE_STRIDE + (irq as usize / 32) * 4;
+        self.write_reg(reg, self.read_reg(reg) | 1 << (irq % 32));
+    }
+
+    pub fn disable(&self, hart: usize, irq: u32) {
+        let reg = ENABLE + self.context(hart) * ENABLE_STRIDE + (irq as usize / 32) * 4;
+        self.write_reg(reg, self.read_reg(reg) & !(1 << (irq % 32)));
+    }
+
+    /// Only interrupts with a priority above the threshold reach the hart.
+    pub fn set_threshold(&self, hart: usize, threshold: u32) {
+        self.write_reg(
+            CONTEXT + self.context(hart) * CONTEXT_STRIDE + THRESHOLD,
+            threshold,
+        );
+    }
+
+    /// Takes the highest priority pending interrupt for the hart.
+    pub fn claim(&self, hart: usize) -> Option<u32> {
+        match self.read_reg(CONTEXT + self.context(hart) * CONTEXT_STRIDE + CLAIM) {
+            0 => None,
+            irq => Some(irq),
+        }
+    }
+
+    /// Signals that a claimed interrupt was handled, so it can be raised again.
+    pub fn complete(&self, hart: usize, irq: u32) {
+        self.write_reg(CONTEXT + self.context(hart) * CONTEXT_STRIDE + CLAIM, irq);
+    }
+
+    fn read_reg(&self, offset: usize) -> u32 {
+        let base = self.base.load(Ordering::Acquire);
+        unsafe { read_volatile((base + offset) as *const u32) }
+    }
+
+    fn write_reg(&self, offset: usize, value: u32) {
+        let base = self.base.load(Ordering::Acquire);
+        unsafe { write_volatile((base + offset) as *mut u32, value) }
+    }
+}
+
+impl Default for Plic {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The S-mode context of a hart when the device tree doesn't say. QEMU's virt machine gives
+/// every hart an M-mode and an S-mode context, in that order.
+fn fallback_context(hart: usize) -> usize {
+    hart * 2 + 1
+}
+
+/// Installs the handler for a device interrupt and unmasks it.
+/// Each interrupt has a single owner, so registering twice is a bug.
+pub fn register_irq_handler(irq: u32, handler: IrqHandler) {
+    assert!(
+        irq != 0 && irq as usize <= PLIC.nr_sources(),
+        "No interrupt source {}",
+        irq
+    );
+    let previous = HANDLERS[irq as usize].swap(handler as usize, Ordering::AcqRel);
+    assert!(
+        previous == 0,
+        "A handler for irq {} was already registered",
+        irq
+    );
+    PLIC.set_priority(irq, DEFAULT_PRIORITY);
+    PLIC.enable(PLIC.hart.load(Ordering::Acquire), irq);
+}
+
+fn registered_handler(irq: u32) -> Option<IrqHandler> {
+    let handler = HANDLERS.get(irq as usize)?.load(Ordering::Acquire);
+    if handler == 0 {
+        return None;
+    }
+    //Safety: Only valid IrqHandlers are ever stored in the table
+    Some(unsafe { core::mem::transmute::<usize, IrqHandler>(handler) })
+}
+
+fn handle_external_interrupt(_frame: &mut TrapFrame, _stval: usize) {
+    let hart = PLIC.hart.load(Ordering::Acquire);
+    while let Some(irq) = PLIC.claim(hart) {
+        match registered_handler(irq) {
+            Some(handler) => handler(irq),
+            None => {
+                warn!("Masking irq {} which has no handler", irq);
+                PLIC.disable(hart, irq);
+            }
+        }
+        PLIC.complete(hart, irq);
+    }
+}
@@ -0,0 +1,52 @@
+//! Power off and reboot by writing a magic value to a system controller register,
+//! as described by the `syscon-poweroff` and `syscon-reboot` device tree bindings.
+//! Only used when the firmware cannot do it for us.
+use core::ptr::write_volatile;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+pub static POWEROFF: SysconReset = SysconReset::new();
+pub static REBOOT: SysconReset = SysconReset::new();
+
+pub struct SysconReset {
+    /// Mapped register to write, 0 when there is none.
+    address: AtomicUsize,
+    value: AtomicUsize,
+    /// Written instead of `value` to report a failure, for devices that can.
+    failure_value: AtomicUsize,
+}
+
+impl SysconReset {
+    pub const fn new() -> Self {
+        SysconReset {
+            address: AtomicUsize::new(0),
+            value: AtomicUsize::new(0),
+            failure_value: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn init(&self, address: usize, value: u32, failure_value: Option<u32>) {
+        self.value.store(value as usize, Ordering::Relaxed);
+        self.failure_value
+            .store(failure_value.unwrap_or(value) as usize, Ordering::Relaxed);
+        self.address.store(address, Ordering::Release);
+    }
+
+    /// Writes the register, which normally doesn't return. Does nothing when not set up.
+    pub fn trigger(&self, failure: bool) {
+        let address = self.address.load(Ordering::Acquire);
+        if address == 0 {
+            return;
+        }
+        let value = match failure {
+            true => self.failure_value.load(Ordering::Relaxed),
+            false => self.value.load(Ordering::Relaxed),
+        };
+        unsafe { write_volatile(address as *mut u32, value as u32) };
+    }
+}
+
+impl Default for SysconReset {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,196 @@
+//! Driver for the NS16550A UART, as found on QEMU's virt machine.
+//! See http://caro.su/msx/ocm_de1/16550.pdf for the registers.
+use crate::{ring_buffer::RingBuffer, spinlock::IrqSpinLock, sync::WaitQueue};
+use core::ptr::{read_volatile, write_volatile};
+
+pub static UART: IrqSpinLock<Uart> = IrqSpinLock::new(Uart::new());
+/// Processes waiting for input, see `wait_for_input`.
+static RX_WAITERS: WaitQueue = WaitQueue::new();
+
+const RX_BUFFER_SIZE: usize = 256;
+const TX_BUFFER_SIZE: usize = 1024;
+/// Bytes the transmitter takes once it reports empty.
+const TX_FIFO_SIZE: usize = 16;
+
+// Register offsets
+const RBR: usize = 0; // Receive buffer (read)
+const THR: usize = 0; // Transmit holding (write)
+const IER: usize = 1; // Interrupt enable
+const FCR: usize = 2; // FIFO control (write)
+const IIR: usize = 2; // Interrupt identification (read)
+const LCR: usize = 3; // Line control
+const MCR: usize = 4; // Modem control
+const LSR: usize = 5; // Line status
+const DLL: usize = 0; // Divisor latch low, when LCR_DLAB is set
+const DLM: usize = 1; // Divisor latch high, when LCR_DLAB is set
+
+const IER_RX_AVAILABLE: u8 = 1 << 0;
+const IER_TX_EMPTY: u8 = 1 << 1;
+const FCR_ENABLE_FIFO: u8 = 1 << 0;
+const FCR_CLEAR_FIFOS: u8 = 0b11 << 1;
+const LCR_8N1: u8 = 0b11;
+const LCR_DLAB: u8 = 1 << 7;
+/// Gates the interrupt line on real hardware.
+const MCR_OUT2: u8 = 1 << 3;
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_TX_EMPTY: u8 = 1 << 5;
+/// 38400 baud with the usual 1.8432 MHz clock. QEMU ignores it.
+const DIVISOR: u16 = 3;
+
+pub struct Uart {
+    base: usize,
+    rx: RingBuffer<RX_BUFFER_SIZE>,
+    tx: RingBuffer<TX_BUFFER_SIZE>,
+    /// Set once our interrupt is routed to us, until then the TX buffer is drained by the writer.
+    irq_enabled: bool,
+}
+
+impl Uart {
+    pub const fn new() -> Self {
+        Uart {
+            base: 0,
+            rx: RingBuffer::new(),
+            tx: RingBuffer::new(),
+            irq_enabled: false,
+        }
+    }
+
+    /// Sets up the UART with its registers at `base`, which must be mapped.
+    pub fn init(&mut self, base: usize) {
+        self.base = base;
+        self.write_reg(IER, 0);
+        self.write_reg(LCR, LCR_DLAB);
+        self.write_reg(DLL, DIVISOR as u8);
+        self.write_reg(DLM, (DIVISOR >> 8) as u8);
+        self.write_reg(LCR, LCR_8N1);
+        self.write_reg(FCR, FCR_ENABLE_FIFO | FCR_CLEAR_FIFOS);
+        self.write_reg(MCR, MCR_OUT2);
+        self.write_reg(IER, IER_RX_AVAILABLE);
+    }
+
+    pub fn is_initialized(&self) -> bool {
+        self.base != 0
+    }
+
+    /// To be called once `handle_interrupt` runs for our interrupt, so output can drain in the background.
+    pub fn enable_irq(&mut self) {
+        self.irq_enabled = true;
+    }
+
+    /// Queues `bytes` for sending. With `blocking`, or without our interrupt,
+    /// this only returns once everything is handed to the hardware.
+    pub fn write(&mut self, bytes: &[u8], blocking: bool) {
+        for &byte in bytes {
+            while !self.tx.push(byte) {
+                self.wait_tx_empty();
+                self.drain_tx();
+            }
+        }
+        self.drain_tx();
+        if blocking || !self.irq_enabled {
+            while !self.tx.is_empty() {
+                self.wait_tx_empty();
+                self.drain_tx();
+            }
+        }
+        self.update_tx_interrupt();
+    }
+
+    /// Next received byte, if any.
+    pub fn read(&mut self) -> Option<u8> {
+        self.rx.pop()
+    }
+
+    /// Moves received bytes into the RX buffer and refills the transmitter. Returns whether anything was received.
+    pub fn handle_interrupt(&mut self) -> bool {
+        // Reading IIR acknowledges a pending TX empty interrupt
+        let _ = self.read_reg(IIR);
+        let mut received = false;
+        while self.read_reg(LSR) & LSR_DATA_READY != 0 {
+            // Input is dropped when nobody reads it
+            self.rx.push(self.read_reg(RBR));
+            received = true;
+        }
+        self.drain_tx();
+        self.update_tx_interrupt();
+        received
+    }
+
+    /// Fills the transmit FIFO if it is empty.
+    fn drain_tx(&mut self) {
+        if self.read_reg(LSR) & LSR_TX_EMPTY == 0 {
+            return;
+        }
+        for _ in 0..TX_FIFO_SIZE {
+            match self.tx.pop() {
+                Some(byte) => self.write_reg(THR, byte),
+                None => break,
+            }
+        }
+    }
+
+    fn wait_tx_empty(&self) {
+        while self.read_reg(LSR) & LSR_TX_EMPTY == 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Only ask for TX empty interrupts while there is something left to send.
+    fn update_tx_interrupt(&mut self) {
+        let mut ier = IER_RX_AVAILABLE;
+        if self.irq_enabled && !self.tx.is_empty() {
+            ier |= IER_TX_EMPTY;
+        }
+        self.write_reg(IER, ier);
+    }
+
+    fn read_reg(&self, reg: usize) -> u8 {
+        unsafe { read_volatile((self.base + reg) as *const u8) }
+    }
+
+    fn write_reg(&self, reg: usize, value: u8) {
+        unsafe { write_volatile((self.base + reg) as *mut u8, value) }
+    }
+}
+
+impl Default for Uart {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Writes to the UART if it is set up, returns false otherwise so the caller can fall back.
+pub fn write(bytes: &[u8], blocking: bool) -> bool {
+    let mut uart = UART.lock();
+    if !uart.is_initialized() {
+        return false;
+    }
+    uart.write(bytes, blocking);
+    true
+}
+
+pub fn is_initialized() -> bool {
+    UART.lock().is_initialized()
+}
+
+pub fn read() -> Option<u8> {
+    UART.lock().read()
+}
+
+/// Blocks until there is input to read. Returns false right away when our interrupt
+/// is not set up, since nothing would wake us then and the caller has to poll instead.
+pub fn wait_for_input() -> bool {
+    if !UART.lock().irq_enabled {
+        return false;
+    }
+    RX_WAITERS.wait_until(|| !UART.lock().rx.is_empty());
+    true
+}
+
+/// Our interrupt handler.
+pub fn handle_interrupt() {
+    // Woken outside of the lock, the scheduler logs through the console
+    if UART.lock().handle_interrupt() {
+        RX_WAITERS.wake_all();
+    }
+}
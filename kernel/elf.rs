@@ -0,0 +1,132 @@
+//! Parsing of statically linked 32-bit RISC-V ELF executables.
+//! See the System V ABI and the RISC-V ELF psABI for the layout.
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS32: u8 = 1;
+const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_RISCV: u16 = 0xF3;
+const ELF_HEADER_SIZE: usize = 52;
+const PROGRAM_HEADER_SIZE: usize = 32;
+
+const PT_LOAD: u32 = 1;
+pub const PF_X: u32 = 1 << 0;
+pub const PF_W: u32 = 1 << 1;
+pub const PF_R: u32 = 1 << 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    TooShort,
+    BadMagic,
+    Not32Bit,
+    NotLittleEndian,
+    NotExecutable,
+    NotRiscV,
+    /// A program header points outside of the file, or has a file size larger than its memory size.
+    BadSegment,
+}
+
+/// A PT_LOAD program header: `file_size` bytes at `offset` go to `virt_address`,
+/// the rest of `mem_size` is zeroed.
+pub struct Segment<'a> {
+    pub virt_address: usize,
+    pub mem_size: usize,
+    pub data: &'a [u8],
+    pub flags: u32,
+}
+
+pub struct Elf<'a> {
+    data: &'a [u8],
+    pub entry: usize,
+    ph_offset: usize,
+    ph_count: usize,
+    ph_entry_size: usize,
+}
+
+impl<'a> Elf<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, ElfError> {
+        if data.len() < ELF_HEADER_SIZE {
+            return Err(ElfError::TooShort);
+        }
+        if data[0..4] != ELF_MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if data[4] != ELFCLASS32 {
+            return Err(ElfError::Not32Bit);
+        }
+        if data[5] != ELFDATA2LSB {
+            return Err(ElfError::NotLittleEndian);
+        }
+        if read_u16(data, 16) != ET_EXEC {
+            return Err(ElfError::NotExecutable);
+        }
+        if read_u16(data, 18) != EM_RISCV {
+            return Err(ElfError::NotRiscV);
+        }
+
+        let elf = Elf {
+            data,
+            entry: read_u32(data, 24) as usize,
+            ph_offset: read_u32(data, 28) as usize,
+            ph_entry_size: read_u16(data, 42) as usize,
+            ph_count: read_u16(data, 44) as usize,
+        };
+        let ph_end = elf
+            .ph_count
+            .checked_mul(elf.ph_entry_size)
+            .and_then(|size| size.checked_add(elf.ph_offset));
+        if elf.ph_entry_size < PROGRAM_HEADER_SIZE || ph_end.is_none_or(|end| end > data.len()) {
+            return Err(ElfError::TooShort);
+        }
+        // Check the segments up front, so loading them cannot fail halfway
+        for segment in elf.raw_segments() {
+            segment?;
+        }
+        Ok(elf)
+    }
+
+    /// The PT_LOAD segments, which is all we need to run a static executable.
+    pub fn segments(&self) -> impl Iterator<Item = Segment<'a>> + '_ {
+        self.raw_segments().filter_map(Result::ok)
+    }
+
+    fn raw_segments(&self) -> impl Iterator<Item = Result<Segment<'a>, ElfError>> + '_ {
+        (0..self.ph_count)
+            .map(|i| self.ph_offset + i * self.ph_entry_size)
+            .filter(|&header| read_u32(self.data, header) == PT_LOAD)
+            .map(|header| {
+                let offset = read_u32(self.data, header + 4) as usize;
+                let virt_address = read_u32(self.data, header + 8) as usize;
+                let file_size = read_u32(self.data, header + 16) as usize;
+                let mem_size = read_u32(self.data, header + 20) as usize;
+                let flags = read_u32(self.data, header + 24);
+                let data = offset
+                    .checked_add(file_size)
+                    .and_then(|end| self.data.get(offset..end))
+                    .ok_or(ElfError::BadSegment)?;
+                if file_size > mem_size || virt_address.checked_add(mem_size).is_none() {
+                    return Err(ElfError::BadSegment);
+                }
+                Ok(Segment {
+                    virt_address,
+                    mem_size,
+                    data,
+                    flags,
+                })
+            })
+    }
+}
+
+// The data is not necessarily aligned (e.g. from include_bytes!), so read byte by byte.
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ])
+}
@@ -0,0 +1,43 @@
+use crate::address_space::Access;
+use crate::arch::{self, Exception, TrapCause, TrapFrame};
+use crate::scheduler::Scheduler;
+use crate::warn;
+
+pub fn init() {
+    arch::register_trap_handler(
+        TrapCause::Exception(Exception::InstructionPageFault),
+        |frame, stval| handle_page_fault(frame, stval, Access::Execute),
+    );
+    arch::register_trap_handler(
+        TrapCause::Exception(Exception::LoadPageFault),
+        |frame, stval| handle_page_fault(frame, stval, Access::Load),
+    );
+    arch::register_trap_handler(
+        TrapCause::Exception(Exception::StorePageFault),
+        |frame, stval| handle_page_fault(frame, stval, Access::Store),
+    );
+}
+
+/// `stval` holds the faulting address. Returning runs the faulting instruction again.
+fn handle_page_fault(frame: &mut TrapFrame, stval: usize, access: Access) {
+    if !frame.from_user() {
+        panic!(
+            "{:?} page fault in the kernel at {:#010x}\n{}",
+            access, stval, frame
+        );
+    }
+    let Err(err) =
+        Scheduler::with_current_memory(|memory| memory.address_space.handle_fault(stval, access))
+    else {
+        return;
+    };
+    warn!(
+        "Process {} killed: {:?} at {:#010x} (pc {:#010x}): {:?}",
+        Scheduler::current_pid(),
+        access,
+        stval,
+        frame.sepc,
+        err
+    );
+    Scheduler::exit_process();
+}
@@ -0,0 +1,485 @@
+//! Parsing of the flattened device tree (FDT) blob the firmware hands us in `a1`.
+//! See the Devicetree Specification, chapter 5, for the layout.
+//!
+//! Nothing is copied: nodes and properties borrow from the blob and are found by walking the
+//! structure block, so the tree can be queried before the page allocator exists.
+
+use core::ops::Range;
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_HEADER_SIZE: usize = 40;
+/// Blobs must stay readable by parsers of this version.
+const FDT_LAST_COMP_VERSION: u32 = 16;
+
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+
+/// Defaults from the spec when a parent has no `#address-cells` or `#size-cells`.
+const DEFAULT_ADDRESS_CELLS: u32 = 2;
+const DEFAULT_SIZE_CELLS: u32 = 1;
+/// Deepest nesting `Fdt::nodes` descends into.
+const MAX_DEPTH: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FdtError {
+    BadMagic,
+    UnsupportedVersion,
+    /// The header points outside of the blob.
+    Truncated,
+}
+
+#[derive(Clone, Copy)]
+pub struct Fdt<'a> {
+    data: &'a [u8],
+    structs: &'a [u8],
+    strings: &'a [u8],
+    mem_rsvmap: usize,
+    pub boot_cpuid: u32,
+}
+
+/// A `reg` entry or a memory reservation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub start: u64,
+    pub size: u64,
+}
+
+impl Region {
+    /// The region as addresses, cut off at the end of our address space.
+    pub fn range(&self) -> Range<usize> {
+        let end = self.start.saturating_add(self.size);
+        self.start.min(usize::MAX as u64) as usize..end.min(usize::MAX as u64) as usize
+    }
+}
+
+pub struct Cpu {
+    pub hart_id: usize,
+    /// False when `status` is set to anything but "okay".
+    pub enabled: bool,
+    /// Phandle of the hart's local interrupt controller, which `interrupts-extended` entries refer to.
+    pub intc_phandle: Option<u32>,
+}
+
+#[derive(Clone, Copy)]
+pub struct Node<'a> {
+    fdt: Fdt<'a>,
+    pub name: &'a str,
+    /// Offset in the structure block of the first token after the node name.
+    offset: usize,
+    /// Cell sizes of `reg`, as declared by the parent.
+    address_cells: u32,
+    size_cells: u32,
+}
+
+#[derive(Clone, Copy)]
+pub struct Property<'a> {
+    pub name: &'a str,
+    pub value: &'a [u8],
+}
+
+impl<'a> Fdt<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, FdtError> {
+        if data.len() < FDT_HEADER_SIZE {
+            return Err(FdtError::Truncated);
+        }
+        if read_u32(data, 0) != Some(FDT_MAGIC) {
+            return Err(FdtError::BadMagic);
+        }
+        let header = |index: usize| read_u32(data, index * 4).unwrap_or(0) as usize;
+        if header(6) as u32 > FDT_LAST_COMP_VERSION {
+            return Err(FdtError::UnsupportedVersion);
+        }
+        let total_size = header(1);
+        let (off_struct, off_strings, mem_rsvmap) = (header(2), header(3), header(4));
+        let (size_strings, size_struct) = (header(8), header(9));
+        if total_size > data.len() || mem_rsvmap >= total_size {
+            return Err(FdtError::Truncated);
+        }
+        let data = &data[..total_size];
+        let structs = data
+            .get(off_struct..off_struct + size_struct)
+            .ok_or(FdtError::Truncated)?;
+        let strings = data
+            .get(off_strings..off_strings + size_strings)
+            .ok_or(FdtError::Truncated)?;
+
+        Ok(Fdt {
+            data,
+            structs,
+            strings,
+            mem_rsvmap,
+            boot_cpuid: header(7) as u32,
+        })
+    }
+
+    /// Parses the blob at `address`, taking its size from the header.
+    ///
+    /// # Safety
+    /// `address` must point to readable memory holding the blob for as long as the tree is used.
+    pub unsafe fn from_address(address: usize) -> Result<Fdt<'static>, FdtError> {
+        let header = unsafe { core::slice::from_raw_parts(address as *const u8, FDT_HEADER_SIZE) };
+        if read_u32(header, 0) != Some(FDT_MAGIC) {
+            return Err(FdtError::BadMagic);
+        }
+        let total_size = read_u32(header, 4).unwrap_or(0) as usize;
+        Fdt::parse(unsafe { core::slice::from_raw_parts(address as *const u8, total_size) })
+    }
+
+    /// Size of the whole blob in bytes.
+    pub fn total_size(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn root(&self) -> Option<Node<'a>> {
+        let mut offset = skip_nops(self.structs, 0);
+        if read_u32(self.structs, offset)? != FDT_BEGIN_NODE {
+            return None;
+        }
+        offset += 4;
+        let name = read_str(self.structs, offset)?;
+        Some(Node {
+            fdt: *self,
+            name,
+            offset: align4(offset + name.len() + 1),
+            address_cells: DEFAULT_ADDRESS_CELLS,
+            size_cells: DEFAULT_SIZE_CELLS,
+        })
+    }
+
+    /// Looks up a node by absolute path, e.g. `/cpus/cpu@0`. Unit addresses may be left out.
+    pub fn find_node(&self, path: &str) -> Option<Node<'a>> {
+        path.split('/')
+            .filter(|part| !part.is_empty())
+            .try_fold(self.root()?, |node, part| node.child(part))
+    }
+
+    /// The node other nodes refer to with `phandle`, e.g. in `interrupt-parent` or `regmap`.
+    pub fn find_phandle(&self, phandle: u32) -> Option<Node<'a>> {
+        self.nodes().find(|node| {
+            node.property_u32("phandle")
+                .or_else(|| node.property_u32("linux,phandle"))
+                == Some(phandle)
+        })
+    }
+
+    /// All nodes, depth first, starting with the root.
+    pub fn nodes(&self) -> Nodes<'a> {
+        Nodes {
+            root: self.root(),
+            stack: [None; MAX_DEPTH],
+            depth: 0,
+        }
+    }
+
+    /// Nodes with `compatible` in their compatible list.
+    pub fn compatible_nodes<'b>(
+        &self,
+        compatible: &'b str,
+    ) -> impl Iterator<Item = Node<'a>> + use<'a, 'b> {
+        self.nodes()
+            .filter(move |node| node.is_compatible(compatible))
+    }
+
+    pub fn find_compatible(&self, compatible: &str) -> Option<Node<'a>> {
+        self.compatible_nodes(compatible).next()
+    }
+
+    /// The `reg` entries of every `memory` node.
+    pub fn memory_regions(&self) -> impl Iterator<Item = Region> + use<'a> {
+        self.root()
+            .into_iter()
+            .flat_map(|root| root.children())
+            .filter(|node| node.property_str("device_type") == Some("memory"))
+            .flat_map(|node| node.reg())
+    }
+
+    /// Entries of the memory reservation block, which ends with an all zero entry.
+    pub fn mem_reservations(&self) -> impl Iterator<Item = Region> + use<'a> {
+        let data = self.data;
+        (self.mem_rsvmap..)
+            .step_by(16)
+            .map(move |offset| {
+                Some(Region {
+                    start: read_u64(data, offset)?,
+                    size: read_u64(data, offset + 8)?,
+                })
+            })
+            .take_while(|region| region.is_some_and(|region| region.size != 0))
+            .flatten()
+    }
+
+    /// Statically placed children of `/reserved-memory`, like the firmware's own memory.
+    pub fn reserved_memory(&self) -> impl Iterator<Item = Region> + use<'a> {
+        self.find_node("/reserved-memory")
+            .into_iter()
+            .flat_map(|reserved| reserved.children())
+            .flat_map(|node| node.reg())
+    }
+
+    /// The kernel command line from `/chosen`.
+    pub fn bootargs(&self) -> Option<&'a str> {
+        self.find_node("/chosen")?.property_str("bootargs")
+    }
+
+    pub fn cpus(&self) -> impl Iterator<Item = Cpu> + use<'a> {
+        self.find_node("/cpus")
+            .into_iter()
+            .flat_map(|cpus| cpus.children())
+            .filter(|node| node.property_str("device_type") == Some("cpu"))
+            .filter_map(|node| {
+                Some(Cpu {
+                    hart_id: node.reg().next()?.start as usize,
+                    enabled: node.property_str("status").is_none_or(|s| s == "okay"),
+                    intc_phandle: node
+                        .child("interrupt-controller")
+                        .and_then(|intc| intc.property_u32("phandle")),
+                })
+            })
+    }
+}
+
+impl<'a> Node<'a> {
+    pub fn properties(&self) -> Properties<'a> {
+        Properties {
+            fdt: self.fdt,
+            offset: self.offset,
+        }
+    }
+
+    pub fn property(&self, name: &str) -> Option<Property<'a>> {
+        self.properties().find(|property| property.name == name)
+    }
+
+    pub fn property_str(&self, name: &str) -> Option<&'a str> {
+        self.property(name)?.as_str()
+    }
+
+    pub fn property_u32(&self, name: &str) -> Option<u32> {
+        self.property(name)?.as_u32()
+    }
+
+    pub fn children(&self) -> Children<'a> {
+        // Children come after all properties
+        let mut properties = self.properties();
+        for _ in properties.by_ref() {}
+        Children {
+            fdt: self.fdt,
+            offset: properties.offset,
+            address_cells: self
+                .property_u32("#address-cells")
+                .unwrap_or(DEFAULT_ADDRESS_CELLS),
+            size_cells: self
+                .property_u32("#size-cells")
+                .unwrap_or(DEFAULT_SIZE_CELLS),
+        }
+    }
+
+    /// Finds a child by full name or by the name without its unit address.
+    pub fn child(&self, name: &str) -> Option<Node<'a>> {
+        self.children()
+            .find(|child| child.name == name || child.name.split('@').next() == Some(name))
+    }
+
+    pub fn compatible(&self) -> impl Iterator<Item = &'a str> + use<'a> {
+        self.property("compatible")
+            .into_iter()
+            .flat_map(|property| property.strings())
+    }
+
+    pub fn is_compatible(&self, compatible: &str) -> bool {
+        self.compatible().any(|entry| entry == compatible)
+    }
+
+    /// The `(address, size)` pairs of the `reg` property.
+    pub fn reg(&self) -> impl Iterator<Item = Region> + use<'a> {
+        let (address_cells, size_cells) = (self.address_cells as usize, self.size_cells as usize);
+        let entry_size = (address_cells + size_cells) * 4;
+        let value = self.property("reg").map_or(&[][..], |reg| reg.value);
+        // A node without address cells has no meaningful reg
+        let entries = if entry_size == 0 { &[][..] } else { value };
+        entries
+            .chunks_exact(entry_size.max(1))
+            .map(move |entry| Region {
+                start: read_cells(entry, 0, address_cells),
+                size: read_cells(entry, address_cells * 4, size_cells),
+            })
+    }
+
+    /// The cells of the `interrupts` property. Our interrupt controllers all use one cell per interrupt.
+    pub fn interrupts(&self) -> impl Iterator<Item = u32> + use<'a> {
+        self.property("interrupts")
+            .into_iter()
+            .flat_map(|property| property.cells())
+    }
+}
+
+impl<'a> Property<'a> {
+    /// The value as a single string, without its terminator.
+    pub fn as_str(&self) -> Option<&'a str> {
+        let value = self.value.strip_suffix(&[0])?;
+        core::str::from_utf8(value).ok()
+    }
+
+    pub fn as_u32(&self) -> Option<u32> {
+        read_u32(self.value, 0)
+    }
+
+    /// The value as a list of strings, like `compatible`.
+    pub fn strings(&self) -> impl Iterator<Item = &'a str> + use<'a> {
+        self.value
+            .split(|&byte| byte == 0)
+            .filter(|s| !s.is_empty())
+            .filter_map(|s| core::str::from_utf8(s).ok())
+    }
+
+    pub fn cells(&self) -> impl Iterator<Item = u32> + use<'a> {
+        self.value
+            .chunks_exact(4)
+            .map(|cell| u32::from_be_bytes([cell[0], cell[1], cell[2], cell[3]]))
+    }
+}
+
+pub struct Properties<'a> {
+    fdt: Fdt<'a>,
+    offset: usize,
+}
+
+impl<'a> Iterator for Properties<'a> {
+    type Item = Property<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let structs = self.fdt.structs;
+        let offset = skip_nops(structs, self.offset);
+        if read_u32(structs, offset)? != FDT_PROP {
+            return None;
+        }
+        let len = read_u32(structs, offset + 4)? as usize;
+        let name_offset = read_u32(structs, offset + 8)? as usize;
+        let value = structs.get(offset + 12..offset + 12 + len)?;
+        let name = read_str(self.fdt.strings, name_offset)?;
+        self.offset = align4(offset + 12 + len);
+        Some(Property { name, value })
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Children<'a> {
+    fdt: Fdt<'a>,
+    offset: usize,
+    address_cells: u32,
+    size_cells: u32,
+}
+
+impl<'a> Iterator for Children<'a> {
+    type Item = Node<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let structs = self.fdt.structs;
+        let offset = skip_nops(structs, self.offset);
+        if read_u32(structs, offset)? != FDT_BEGIN_NODE {
+            return None;
+        }
+        let name = read_str(structs, offset + 4)?;
+        let node_offset = align4(offset + 4 + name.len() + 1);
+        self.offset = skip_node(structs, node_offset)?;
+        Some(Node {
+            fdt: self.fdt,
+            name,
+            offset: node_offset,
+            address_cells: self.address_cells,
+            size_cells: self.size_cells,
+        })
+    }
+}
+
+pub struct Nodes<'a> {
+    root: Option<Node<'a>>,
+    stack: [Option<Children<'a>>; MAX_DEPTH],
+    depth: usize,
+}
+
+impl<'a> Iterator for Nodes<'a> {
+    type Item = Node<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(root) = self.root.take() {
+            self.stack[0] = Some(root.children());
+            self.depth = 1;
+            return Some(root);
+        }
+        while self.depth > 0 {
+            match self.stack[self.depth - 1].as_mut().and_then(|c| c.next()) {
+                Some(node) => {
+                    if self.depth < MAX_DEPTH {
+                        self.stack[self.depth] = Some(node.children());
+                        self.depth += 1;
+                    }
+                    return Some(node);
+                }
+                None => self.depth -= 1,
+            }
+        }
+        None
+    }
+}
+
+/// Returns the offset right after the FDT_END_NODE closing the node whose content starts at `offset`.
+fn skip_node(structs: &[u8], mut offset: usize) -> Option<usize> {
+    let mut depth = 1;
+    while depth > 0 {
+        match read_u32(structs, offset)? {
+            FDT_BEGIN_NODE => {
+                let name = read_str(structs, offset + 4)?;
+                offset = align4(offset + 4 + name.len() + 1);
+                depth += 1;
+            }
+            FDT_END_NODE => {
+                offset += 4;
+                depth -= 1;
+            }
+            FDT_PROP => {
+                let len = read_u32(structs, offset + 4)? as usize;
+                offset = align4(offset + 12 + len);
+            }
+            FDT_NOP => offset += 4,
+            // FDT_END or garbage, the tree is malformed
+            _ => return None,
+        }
+    }
+    Some(offset)
+}
+
+fn skip_nops(structs: &[u8], mut offset: usize) -> usize {
+    while read_u32(structs, offset) == Some(FDT_NOP) {
+        offset += 4;
+    }
+    offset
+}
+
+const fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+/// Reads a number made up of `cells` big-endian 32-bit cells.
+fn read_cells(data: &[u8], offset: usize, cells: usize) -> u64 {
+    (0..cells).fold(0, |value, cell| {
+        (value << 32) | read_u32(data, offset + cell * 4).unwrap_or(0) as u64
+    })
+}
+
+fn read_str(data: &[u8], offset: usize) -> Option<&str> {
+    let bytes = data.get(offset..)?;
+    let len = bytes.iter().position(|&byte| byte == 0)?;
+    core::str::from_utf8(&bytes[..len]).ok()
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset + 4)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Option<u64> {
+    Some(((read_u32(data, offset)? as u64) << 32) | read_u32(data, offset + 4)? as u64)
+}
@@ -4,24 +4,45 @@ extern crate alloc;
 use alloc::vec::Vec;
 use allocator::KernelAllocator;
 use core::panic::PanicInfo;
-use scheduler::Scheduler;
-use spinlock::SpinLock;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+use scheduler::{Scheduler, SCHEDULER};
+use spinlock::IrqSpinLock;
 
+pub mod address_space;
 pub mod allocator;
 pub mod arch;
 pub mod common;
+pub mod cpu;
+pub mod drivers;
+pub mod elf;
+pub mod fault;
+pub mod fdt;
+#[cfg(feature = "lock-debug")]
+pub mod lockdep;
+pub mod log;
+pub mod monitor;
 pub mod page;
 pub mod page_table;
 pub mod process;
+pub mod ring_buffer;
 pub mod scheduler;
 pub mod spinlock;
+pub mod sync;
+pub mod syscall;
 
 #[global_allocator]
 static ALLOCATOR: KernelAllocator = KernelAllocator;
-static ROOT_PAGE_TABLE: SpinLock<page_table::PageTable> =
-    SpinLock::new(page_table::PageTable::new());
+static ROOT_PAGE_TABLE: IrqSpinLock<page_table::PageTable> =
+    IrqSpinLock::new(page_table::PageTable::new());
 
+/// Addresses are where the kernel reaches things, in the direct map. See `arch::phys_to_virt`.
 pub struct BootInfo {
+    /// Hart we booted on and the device tree blob, as handed over by the firmware.
+    pub hart_id: usize,
+    pub dtb_address: usize,
+    /// The whole kernel image, up to and including the boot stack.
+    pub kernel_start: usize,
+    pub kernel_end: usize,
     pub text_start: usize,
     pub text_end: usize,
     pub rodata_start: usize,
@@ -32,12 +53,11 @@ pub struct BootInfo {
     pub bss_end: usize,
     pub stack_start: usize,
     pub stack_end: usize,
-    pub heap_start: usize,
-    pub heap_end: usize,
 }
 
 pub fn boot(boot_info: &BootInfo) {
     arch::init_handlers();
+    cpu::init_boot(boot_info.hart_id);
 
     println!("===============================================");
     println!("      OOOOO   X     X   III  V         V ");
@@ -49,27 +69,50 @@ pub fn boot(boot_info: &BootInfo) {
     println!("      OOOOO   X     X   III       V      ");
     println!("===============================================");
     println!("{}", "Hello World!");
+    println!("Booted on hart {}", boot_info.hart_id);
+    arch::sbi::init();
+    let fdt = unsafe { fdt::Fdt::from_address(boot_info.dtb_address) }
+        .expect("No valid device tree passed by the firmware");
+    print_device_tree(&fdt);
+    log::init(fdt.bootargs().unwrap_or(""));
+    init_panic_action(fdt.bootargs().unwrap_or(""));
+    println!();
     unsafe {
-        init_memory(boot_info);
+        init_memory(boot_info, &fdt);
     }
     println!();
-    init_stap(&ROOT_PAGE_TABLE as *const _ as usize);
+    init_devices(&fdt, boot_info.hart_id);
+    println!();
+    init_stap(arch::virt_to_phys(
+        &*ROOT_PAGE_TABLE.lock() as *const _ as usize
+    ));
     println!();
     unsafe {
         do_mem_tests();
     }
     println!();
-    unsafe {
-        init_scheduler();
-    }
+    init_scheduler();
+    syscall::init();
+    fault::init();
+    println!();
+    let satp = arch::Satp::new(arch::virt_to_phys(
+        &*ROOT_PAGE_TABLE.lock() as *const _ as usize
+    ));
+    cpu::start_secondaries(&fdt, &satp, secondary_boot);
     println!();
     println!("Kernel initialization done. Going phase 2! Prepare to enter user mode.");
-    println!("(Actually not yet, since that's not finised yet. For now just test some process scheduling)");
     println!("===============================================");
     println!();
-    unsafe {
-        yield_to_init();
-    }
+    yield_to_init();
+}
+
+/// Where the other harts start, with paging on and `tp` pointing at their per-CPU block.
+extern "C" fn secondary_boot() -> ! {
+    arch::init_handlers();
+    arch::enable_software_interrupts();
+    SCHEDULER.lock().init_cpu();
+    cpu::current().set_online();
+    Scheduler::start();
 }
 
 //TODO: This should also be abstracted away in arch
@@ -80,42 +123,72 @@ fn init_stap(addr: usize) {
     println!("Stap register written")
 }
 
+/// User programs built from `user/bin` by our build script.
+static HELLO_ELF: &[u8] = include_bytes!(env!("USER_HELLO_ELF"));
+static SPIN_ELF: &[u8] = include_bytes!(env!("USER_SPIN_ELF"));
+/// The programs that can be started by name, e.g. from the monitor.
+static PROGRAMS: [(&str, &[u8]); 2] = [("hello", HELLO_ELF), ("spin", SPIN_ELF)];
+
 // Idea is to have this start the init process. But this is not yet implemented
-unsafe fn yield_to_init() {
-    println!("Starting process A and B");
-    let proc_a_ptr = process_a as *const () as usize;
-    let proc_b_ptr = process_b as *const () as usize;
-    assert!(
-        proc_a_ptr % 2 == 0,
-        "proc_a_ptr is not 4-byte aligned: {:#x}",
-        proc_a_ptr
-    );
-    assert!(
-        proc_b_ptr % 2 == 0,
-        "proc_b_ptr is not 4-byte aligned: {:#x}",
-        proc_b_ptr
-    );
-    println!("proc_a_ptr: {:#X}", proc_a_ptr);
-    println!("proc_b_ptr: {:#X}", proc_b_ptr);
-    let proc_a = CREATOR.as_mut().unwrap().schedule_process(proc_a_ptr);
-    println!("A: {}", proc_a);
-    let proc_b = CREATOR.as_mut().unwrap().schedule_process(proc_b_ptr);
-    println!("B: {}", proc_b);
-    CREATOR.as_mut().unwrap().yield_control();
+fn yield_to_init() -> ! {
+    println!("Starting user processes");
+    let programs: [(&[u8], &[&str]); 3] = [
+        (HELLO_ELF, &["hello", "from", "the kernel"]),
+        (SPIN_ELF, &["spin"]),
+        (SPIN_ELF, &["spin"]),
+    ];
+    for (elf, argv) in programs {
+        match SCHEDULER
+            .lock()
+            .schedule_user_process(elf, argv, &["OS=oxiv"])
+        {
+            Ok(info) => println!("{}: {}", argv[0], info),
+            Err(error) => println!("Could not start {}: {:?}", argv[0], error),
+        }
+    }
+    let monitor = SCHEDULER
+        .lock()
+        .schedule_process(monitor::run as *const () as usize);
+    println!("monitor: {}", monitor);
+    Scheduler::start();
 }
 
-unsafe fn init_scheduler() {
+fn print_device_tree(fdt: &fdt::Fdt) {
+    println!("Device tree ({} bytes):", fdt.total_size());
+    for region in fdt.memory_regions() {
+        println!(
+            "MEMORY: 0x{:x} -> 0x{:x}",
+            region.start,
+            region.start + region.size
+        );
+    }
+    for region in fdt.mem_reservations().chain(fdt.reserved_memory()) {
+        println!(
+            "RESERVED: 0x{:x} -> 0x{:x}",
+            region.start,
+            region.start + region.size
+        );
+    }
+    for cpu in fdt.cpus() {
+        println!(
+            "CPU: hart {}{}",
+            cpu.hart_id,
+            if cpu.enabled { "" } else { " (disabled)" }
+        );
+    }
+    println!("BOOTARGS: {:?}", fdt.bootargs().unwrap_or(""));
+}
+
+fn init_scheduler() {
     println!("Initing Scheduler...");
-    CREATOR = Some(Scheduler::new());
-    CREATOR.as_mut().unwrap().init();
+    SCHEDULER.lock().init();
     println!("Scheduler inited!");
 }
 
-unsafe fn init_memory(boot_info: &BootInfo) {
+unsafe fn init_memory(boot_info: &BootInfo, fdt: &fdt::Fdt) {
+    let memory = usable_memory(boot_info, fdt);
     println!("Initiating Page Alloctor: ");
-    page::PAGE_ALLOCATOR
-        .lock()
-        .init(boot_info.heap_start, boot_info.heap_end);
+    page::PAGE_ALLOCATOR.lock().init(&memory);
     page::PAGE_ALLOCATOR.lock().print_page_allocations();
     println!();
     println!("Mapping kernel space:");
@@ -139,10 +212,6 @@ unsafe fn init_memory(boot_info: &BootInfo) {
         "STACK:  0x{:x} -> 0x{:x}",
         boot_info.stack_start, boot_info.stack_end
     );
-    println!(
-        "HEAP:   0x{:x} -> 0x{:x}",
-        boot_info.heap_start, boot_info.heap_end
-    );
 
     let mut root_page = ROOT_PAGE_TABLE.lock();
     assert!(
@@ -150,51 +219,233 @@ unsafe fn init_memory(boot_info: &BootInfo) {
         "ROOT_PAGE_TABLE is not aligned!"
     );
 
-    root_page.map_kernel_range(
-        page_table::VirtualAddress(boot_info.text_start),
-        page_table::VirtualAddress(boot_info.text_end),
+    map_direct(
+        &mut root_page,
+        boot_info.text_start,
+        boot_info.text_end,
         page_table::EntryFlags::Read as usize | page_table::EntryFlags::Execute as usize,
     );
 
-    root_page.map_kernel_range(
-        page_table::VirtualAddress(boot_info.rodata_start),
-        page_table::VirtualAddress(boot_info.rodata_end),
+    map_direct(
+        &mut root_page,
+        boot_info.rodata_start,
+        boot_info.rodata_end,
         page_table::EntryFlags::Read as usize | page_table::EntryFlags::Execute as usize,
     );
 
-    root_page.map_kernel_range(
-        page_table::VirtualAddress(boot_info.data_start),
-        page_table::VirtualAddress(boot_info.data_end),
+    map_direct(
+        &mut root_page,
+        boot_info.data_start,
+        boot_info.data_end,
         page_table::EntryFlags::Read as usize | page_table::EntryFlags::Write as usize,
     );
 
-    root_page.map_kernel_range(
-        page_table::VirtualAddress(boot_info.bss_start),
-        page_table::VirtualAddress(boot_info.bss_end),
+    map_direct(
+        &mut root_page,
+        boot_info.bss_start,
+        boot_info.bss_end,
         page_table::EntryFlags::Read as usize | page_table::EntryFlags::Write as usize,
     );
 
-    root_page.map_kernel_range(
-        page_table::VirtualAddress(boot_info.stack_start),
-        page_table::VirtualAddress(boot_info.stack_end),
+    map_direct(
+        &mut root_page,
+        boot_info.stack_start,
+        boot_info.stack_end,
         page_table::EntryFlags::Read as usize | page_table::EntryFlags::Write as usize,
     );
+    // Keep the device tree readable once paging is on
+    let dtb_start = boot_info.dtb_address & !(arch::PAGE_SIZE - 1);
+    println!(
+        "DTB:    0x{:x} -> 0x{:x}",
+        boot_info.dtb_address,
+        boot_info.dtb_address + fdt.total_size()
+    );
+    map_direct(
+        &mut root_page,
+        dtb_start,
+        boot_info.dtb_address + fdt.total_size(),
+        page_table::EntryFlags::Read as usize,
+    );
     println!();
-    println!("Detailed ROOT_PAGE_TABLE view before heap map:");
+    println!("Detailed ROOT_PAGE_TABLE view before free memory map:");
     root_page.print_entries(false);
     println!();
 
-    root_page.map_kernel_range(
-        page_table::VirtualAddress(boot_info.heap_start),
-        page_table::VirtualAddress(boot_info.heap_end),
-        page_table::EntryFlags::Read as usize | page_table::EntryFlags::Write as usize,
-    );
-    println!("First-level ROOT_PAGE_TABLE view after heap map:");
+    for range in memory.ranges() {
+        println!("FREE:   0x{:x} -> 0x{:x}", range.start, range.end);
+        map_direct(
+            &mut root_page,
+            arch::phys_to_virt(range.start),
+            range.end + arch::DIRECT_MAP_OFFSET,
+            page_table::EntryFlags::Read as usize | page_table::EntryFlags::Write as usize,
+        );
+    }
+    println!("First-level ROOT_PAGE_TABLE view after free memory map:");
     root_page.print_entries(false);
     println!();
     println!("Mapping kernel space done!");
 }
 
+const SIFIVE_TEST_FAIL: u32 = 0x3333;
+
+/// Maps [start, end) of the direct map to the physical memory behind it.
+fn map_direct(root_page: &mut page_table::PageTable, start: usize, end: usize, flags: usize) {
+    root_page.map_kernel_range(
+        page_table::VirtualAddress(start),
+        page_table::VirtualAddress(end),
+        page_table::PhysicalAddress(arch::virt_to_phys(start) as u64),
+        flags,
+    );
+}
+
+/// Next free address in the device window, see `map_device`.
+static NEXT_DEVICE_ADDRESS: AtomicUsize = AtomicUsize::new(arch::MMIO_BASE);
+
+/// Maps the registers of a device node into the device window of the kernel address space,
+/// returning the address they are at now.
+fn map_device(node: &fdt::Node) -> Option<usize> {
+    let range = node.reg().next()?.range();
+    let start = range.start & !(arch::PAGE_SIZE - 1);
+    let size = page::align_val(range.end, arch::PAGE_ORDER) - start;
+    let virt_start = NEXT_DEVICE_ADDRESS.fetch_add(size, Ordering::Relaxed);
+    if arch::MMIO_END - virt_start < size {
+        println!("No room left to map the registers at 0x{:x}", range.start);
+        return None;
+    }
+    ROOT_PAGE_TABLE.lock().map_kernel_range(
+        page_table::VirtualAddress(virt_start),
+        page_table::VirtualAddress(virt_start + size),
+        page_table::PhysicalAddress(start as u64),
+        page_table::EntryFlags::Read as usize | page_table::EntryFlags::Write as usize,
+    );
+    Some(virt_start + (range.start - start))
+}
+
+/// Each `interrupts-extended` entry of the PLIC is one of its contexts, in order. The S-mode context of a
+/// hart is the one that raises the supervisor external interrupt on the hart's interrupt controller.
+fn find_plic_contexts(fdt: &fdt::Fdt, plic: &fdt::Node) {
+    let Some(property) = plic.property("interrupts-extended") else {
+        return;
+    };
+    let mut cells = property.cells();
+    let mut context = 0;
+    while let Some(phandle) = cells.next() {
+        // Without the controller we don't know how many cells the entry has
+        let Some(controller) = fdt.find_phandle(phandle) else {
+            break;
+        };
+        let nr_cells = controller.property_u32("#interrupt-cells").unwrap_or(1);
+        let irq = cells.next();
+        for _ in 1..nr_cells {
+            cells.next();
+        }
+        if irq == Some(arch::Interrupt::SupervisorExternal as u32) {
+            if let Some(cpu) = fdt.cpus().find(|cpu| cpu.intc_phandle == Some(phandle)) {
+                drivers::plic::PLIC.set_context(cpu.hart_id, context);
+            }
+        }
+        context += 1;
+    }
+}
+
+/// Sets up the devices we have drivers for. Their registers have to be mapped
+/// before any process copies the kernel part of the page table.
+fn init_devices(fdt: &fdt::Fdt, hart_id: usize) {
+    println!("Initiating devices:");
+    let plic = fdt
+        .find_compatible("riscv,plic0")
+        .or_else(|| fdt.find_compatible("sifive,plic-1.0.0"));
+    let plic_ready = match plic.and_then(|node| Some((map_device(&node)?, node))) {
+        Some((base, node)) => {
+            let nr_sources = node.property_u32("riscv,ndev").unwrap_or(0) as usize;
+            find_plic_contexts(fdt, &node);
+            drivers::plic::PLIC.init(base, nr_sources, hart_id);
+            println!("PLIC:   0x{:x} ({} sources)", base, nr_sources);
+            true
+        }
+        None => {
+            println!("No PLIC found, running without external interrupts");
+            false
+        }
+    };
+
+    match fdt.find_compatible("ns16550a").and_then(|node| {
+        let base = map_device(&node)?;
+        Some((base, node.interrupts().next()))
+    }) {
+        Some((base, irq)) => {
+            drivers::uart::UART.lock().init(base);
+            println!("UART:   0x{:x} (irq {:?})", base, irq);
+            if let Some(irq) = irq.filter(|_| plic_ready) {
+                drivers::plic::register_irq_handler(irq, |_| drivers::uart::handle_interrupt());
+                drivers::uart::UART.lock().enable_irq();
+            }
+        }
+        None => println!("No UART found, staying on the SBI console"),
+    }
+
+    for (compatible, reset) in [
+        ("syscon-poweroff", &drivers::syscon::POWEROFF),
+        ("syscon-reboot", &drivers::syscon::REBOOT),
+    ] {
+        let Some(node) = fdt.find_compatible(compatible) else {
+            continue;
+        };
+        let (Some(regmap), Some(value)) = (
+            node.property_u32("regmap")
+                .and_then(|phandle| fdt.find_phandle(phandle)),
+            node.property_u32("value"),
+        ) else {
+            continue;
+        };
+        let Some(base) = map_device(&regmap) else {
+            continue;
+        };
+        let address = base + node.property_u32("offset").unwrap_or(0) as usize;
+        // The SiFive test device (QEMU's virt) takes an exit code along with its fail value
+        let failure_value = regmap
+            .is_compatible("sifive,test0")
+            .then_some(SIFIVE_TEST_FAIL | 1 << 16);
+        reset.init(address, value, failure_value);
+        println!("{}: 0x{:x} = 0x{:x}", compatible, address, value);
+    }
+}
+
+/// RAM from the device tree, minus the kernel image, the device tree itself and whatever the firmware reserved.
+/// Only RAM in the direct map can be used. Its very last page is left out, so the end of a range in
+/// the direct map still fits in a usize.
+fn usable_memory(boot_info: &BootInfo, fdt: &fdt::Fdt) -> page::MemoryMap {
+    let direct_map_end = arch::DIRECT_MAP_PHYS + arch::DIRECT_MAP_SIZE - arch::PAGE_SIZE;
+    let mut memory = page::MemoryMap::new();
+    for region in fdt.memory_regions() {
+        let range = region.range();
+        let start = range.start.max(arch::DIRECT_MAP_PHYS);
+        let end = range.end.min(direct_map_end);
+        if start >= end {
+            println!(
+                "RAM 0x{:x} -> 0x{:x} is outside the direct map, skipping it",
+                range.start, range.end
+            );
+            continue;
+        }
+        if (start, end) != (range.start, range.end) {
+            println!(
+                "Only using 0x{:x} -> 0x{:x} of RAM 0x{:x} -> 0x{:x}, the rest is outside the direct map",
+                start, end, range.start, range.end
+            );
+        }
+        memory.add(start..end);
+    }
+    let kernel_start = arch::virt_to_phys(boot_info.kernel_start);
+    let dtb_address = arch::virt_to_phys(boot_info.dtb_address);
+    memory.remove(kernel_start..arch::virt_to_phys(boot_info.kernel_end));
+    memory.remove(dtb_address..dtb_address + fdt.total_size());
+    for region in fdt.mem_reservations().chain(fdt.reserved_memory()) {
+        memory.remove(region.range());
+    }
+    memory
+}
+
 #[allow(static_mut_refs)]
 unsafe fn do_mem_tests() {
     println!("Basic memory initialization done! Testing some allocations...");
@@ -225,40 +476,61 @@ unsafe fn do_mem_tests() {
     println!("Mem test test done!");
 }
 
-/// Since yield_control does not return, we cannot just use our spin-lock to get rid of the static.
-/// If we did, we would give control to proc A, yet it would get in the spin when trying to yield itself
-/// This will fix itself after we implement a timer interrupt for sheduling.
-static mut CREATOR: Option<Scheduler> = None;
-
-#[allow(static_mut_refs)]
-unsafe fn process_a() {
-    println!("Printing a 3 A's");
-    for i in 0..3 {
-        println!("A{}", i);
-        arch::delay();
-        CREATOR.as_mut().unwrap().yield_control();
-    }
-    println!("A was done!");
-    CREATOR.as_mut().unwrap().exit_process();
+/// What the panic handler does once the panic is reported.
+#[derive(Clone, Copy)]
+#[repr(u8)]
+enum PanicAction {
+    /// Power off, reporting a failure
+    Shutdown = 0,
+    Reboot = 1,
+    /// Stay around, e.g. to attach a debugger
+    Halt = 2,
 }
-#[allow(static_mut_refs)]
-unsafe fn process_b() {
-    println!("Printing a 3 B's");
-    for i in 0..3 {
-        println!("B{}", i);
-        arch::delay();
-        CREATOR.as_mut().unwrap().yield_control();
-    }
-    println!("B was done!");
-    CREATOR.as_mut().unwrap().exit_process();
+
+static PANIC_ACTION: AtomicU8 = AtomicU8::new(PanicAction::Shutdown as u8);
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+/// Picks the panic action from `panic=shutdown|reboot|halt` on the kernel command line.
+fn init_panic_action(cmdline: &str) {
+    let Some(action) = cmdline
+        .split_whitespace()
+        .find_map(|option| option.strip_prefix("panic="))
+    else {
+        return;
+    };
+    let action = match action {
+        "shutdown" => PanicAction::Shutdown,
+        "reboot" => PanicAction::Reboot,
+        "halt" => PanicAction::Halt,
+        _ => {
+            println!("Unknown panic action '{}', keeping shutdown", action);
+            return;
+        }
+    };
+    PANIC_ACTION.store(action as u8, Ordering::Relaxed);
 }
 
 #[panic_handler]
 fn handle_panic(info: &PanicInfo) -> ! {
-    print!("Kernel Panic");
-    if let Some(location) = info.location() {
-        print!(" ({},{})", location.line(), location.column())
+    arch::disable_interrupts();
+    if PANICKING.swap(true, Ordering::Relaxed) {
+        // Reporting the first panic panicked too, e.g. on the console lock, so stay away from the console
+        common::raw_print_args(format_args!(
+            "Kernel Panic while panicking: {}\n",
+            info.message()
+        ));
+    } else {
+        // So the others don't go on running processes and printing on a kernel that panicked
+        cpu::stop_others();
+        print!("Kernel Panic");
+        if let Some(location) = info.location() {
+            print!(" ({},{})", location.line(), location.column())
+        }
+        println!(": {}", info.message());
+    }
+    match PANIC_ACTION.load(Ordering::Relaxed) {
+        action if action == PanicAction::Reboot as u8 => arch::reboot(),
+        action if action == PanicAction::Halt as u8 => arch::abort(),
+        _ => arch::shutdown_with(arch::ExitStatus::Failure),
     }
-    print!(": {}", info.message());
-    arch::abort();
 }
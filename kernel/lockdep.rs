@@ -0,0 +1,236 @@
+//! Lock debugging, built with the `lock-debug` feature. Catches a hart taking a lock it already holds,
+//! reports locks spun on for suspiciously long, and learns the order locks are taken in to report inversions.
+//! Locks are told apart by their address, which suits the statics this is meant for.
+//! Reports go straight to the firmware console, since the console lock may be the one in trouble.
+use crate::cpu::{self, MAX_CPUS};
+use core::fmt::{self, Display};
+use core::panic::Location;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, AtomicUsize, Ordering};
+
+/// Spins after which a lock is reported as a suspected deadlock.
+const SPIN_LIMIT: usize = 1 << 24;
+/// Locks a hart can hold at once and still have them checked.
+const MAX_HELD: usize = 16;
+/// Locks that fit in the order graph, one bit each.
+const MAX_CLASSES: usize = 32;
+const NO_OWNER: usize = usize::MAX;
+
+macro_rules! report {
+    ($($arg:tt)*) => ($crate::common::raw_print_args(format_args!("lockdep: {}\n", format_args!($($arg)*))));
+}
+
+/// Address of the lock behind each class, the index in the order graph.
+static CLASSES: [AtomicUsize; MAX_CLASSES] = [const { AtomicUsize::new(0) }; MAX_CLASSES];
+static CLASSES_FULL: AtomicBool = AtomicBool::new(false);
+/// Bit `j` of entry `i` is set once class `j` was taken while holding class `i`.
+static TAKEN_AFTER: [AtomicU32; MAX_CLASSES] = [const { AtomicU32::new(0) }; MAX_CLASSES];
+/// Inversions reported already, laid out like `TAKEN_AFTER`.
+static REPORTED: [AtomicU32; MAX_CLASSES] = [const { AtomicU32::new(0) }; MAX_CLASSES];
+
+/// The locks each hart holds, in the order it took them.
+static HELD: [[Held; MAX_HELD]; MAX_CPUS] = [const { [const { Held::new() }; MAX_HELD] }; MAX_CPUS];
+/// Can go past `MAX_HELD`, the locks beyond it just aren't recorded.
+static HELD_LEN: [AtomicUsize; MAX_CPUS] = [const { AtomicUsize::new(0) }; MAX_CPUS];
+
+struct Held {
+    lock: AtomicUsize,
+    site: AtomicPtr<Location<'static>>,
+}
+
+impl Held {
+    const fn new() -> Self {
+        Held {
+            lock: AtomicUsize::new(0),
+            site: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+}
+
+/// Where a lock was taken, if we know.
+struct Site(*const Location<'static>);
+
+impl Display for Site {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match unsafe { self.0.as_ref() } {
+            Some(location) => write!(f, "{}", location),
+            None => write!(f, "<unknown>"),
+        }
+    }
+}
+
+/// The hart holding a lock. It may have just released it.
+struct Owner(usize);
+
+impl Display for Owner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            NO_OWNER => write!(f, "<nobody>"),
+            hart => write!(f, "hart {}", hart),
+        }
+    }
+}
+
+fn as_ptr(site: &'static Location<'static>) -> *mut Location<'static> {
+    site as *const _ as *mut _
+}
+
+/// What a lock keeps for debugging: who holds it, and where they took it.
+pub struct LockDebug {
+    owner: AtomicUsize,
+    site: AtomicPtr<Location<'static>>,
+}
+
+impl LockDebug {
+    pub const fn new() -> Self {
+        LockDebug {
+            owner: AtomicUsize::new(NO_OWNER),
+            site: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    /// Checks taking `lock` at `site` against the locks this hart holds, before spinning on it.
+    pub fn acquire(&self, lock: usize, site: &'static Location<'static>) {
+        let hart = cpu::current().hart_id();
+        if self.owner.load(Ordering::Relaxed) == hart {
+            panic!(
+                "Hart {} takes lock {:#x} again at {}, it holds it since {}",
+                hart,
+                lock,
+                site,
+                Site(self.site.load(Ordering::Relaxed))
+            );
+        }
+        check_order(lock, site);
+    }
+
+    /// Called on every spin, with how many there were so far.
+    pub fn spinning(&self, lock: usize, site: &'static Location<'static>, spins: usize) {
+        if spins == SPIN_LIMIT {
+            report!(
+                "suspected deadlock, hart {} spins on lock {:#x} at {}, held by {} since {}",
+                cpu::current().hart_id(),
+                lock,
+                site,
+                Owner(self.owner.load(Ordering::Relaxed)),
+                Site(self.site.load(Ordering::Relaxed))
+            );
+        }
+    }
+
+    pub fn acquired(&self, lock: usize, site: &'static Location<'static>) {
+        self.owner
+            .store(cpu::current().hart_id(), Ordering::Relaxed);
+        self.site.store(as_ptr(site), Ordering::Relaxed);
+        push_held(lock, site);
+    }
+
+    pub fn released(&self, lock: usize) {
+        self.owner.store(NO_OWNER, Ordering::Relaxed);
+        self.site.store(ptr::null_mut(), Ordering::Relaxed);
+        pop_held(lock);
+    }
+}
+
+impl Default for LockDebug {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Records that `lock` comes after every lock this hart holds, unless that was the other way around before.
+fn check_order(lock: usize, site: &'static Location<'static>) {
+    let Some(class) = class_of(lock) else {
+        return;
+    };
+    let cpu = cpu::current().index();
+    let len = HELD_LEN[cpu].load(Ordering::Relaxed).min(MAX_HELD);
+    for held in &HELD[cpu][..len] {
+        let held_lock = held.lock.load(Ordering::Relaxed);
+        let Some(held_class) = class_of(held_lock) else {
+            continue;
+        };
+        if held_class == class {
+            continue;
+        }
+        if !reaches(class, held_class) {
+            TAKEN_AFTER[held_class].fetch_or(1 << class, Ordering::Relaxed);
+        } else if REPORTED[held_class].fetch_or(1 << class, Ordering::Relaxed) & 1 << class == 0 {
+            report!(
+                "lock order inversion, lock {:#x} taken at {} while holding lock {:#x} taken at {}, \
+                 elsewhere these are taken the other way around",
+                lock,
+                site,
+                held_lock,
+                Site(held.site.load(Ordering::Relaxed))
+            );
+        }
+    }
+}
+
+/// Whether class `to` was ever taken after class `from`, possibly through other locks.
+fn reaches(from: usize, to: usize) -> bool {
+    let mut seen = 0u32;
+    let mut frontier = 1u32 << from;
+    while frontier != 0 {
+        seen |= frontier;
+        let mut next = 0;
+        let mut rest = frontier;
+        while rest != 0 {
+            let class = rest.trailing_zeros() as usize;
+            rest &= rest - 1;
+            next |= TAKEN_AFTER[class].load(Ordering::Relaxed);
+        }
+        frontier = next & !seen;
+    }
+    seen & 1 << to != 0
+}
+
+/// The class of `lock`, given one the first time it is seen. `None` once there are too many.
+fn class_of(lock: usize) -> Option<usize> {
+    for (index, class) in CLASSES.iter().enumerate() {
+        match class.compare_exchange(0, lock, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => return Some(index),
+            Err(existing) if existing == lock => return Some(index),
+            Err(_) => {}
+        }
+    }
+    if !CLASSES_FULL.swap(true, Ordering::Relaxed) {
+        report!(
+            "more than {} locks, not checking the order of the rest",
+            MAX_CLASSES
+        );
+    }
+    None
+}
+
+fn push_held(lock: usize, site: &'static Location<'static>) {
+    let cpu = cpu::current().index();
+    let len = HELD_LEN[cpu].load(Ordering::Relaxed);
+    if let Some(held) = HELD[cpu].get(len) {
+        held.lock.store(lock, Ordering::Relaxed);
+        held.site.store(as_ptr(site), Ordering::Relaxed);
+    }
+    HELD_LEN[cpu].store(len + 1, Ordering::Relaxed);
+}
+
+/// Locks don't have to be released in the order they were taken.
+fn pop_held(lock: usize) {
+    let cpu = cpu::current().index();
+    let len = HELD_LEN[cpu].load(Ordering::Relaxed);
+    let held = &HELD[cpu][..len.min(MAX_HELD)];
+    if let Some(index) = held
+        .iter()
+        .rposition(|held| held.lock.load(Ordering::Relaxed) == lock)
+    {
+        for i in index..held.len() - 1 {
+            held[i]
+                .lock
+                .store(held[i + 1].lock.load(Ordering::Relaxed), Ordering::Relaxed);
+            held[i]
+                .site
+                .store(held[i + 1].site.load(Ordering::Relaxed), Ordering::Relaxed);
+        }
+    }
+    HELD_LEN[cpu].store(len.saturating_sub(1), Ordering::Relaxed);
+}
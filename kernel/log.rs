@@ -0,0 +1,344 @@
+//! Leveled kernel logging.
+//!
+//! Every message that passes the filter goes into an in-memory ring buffer, which `dump` prints
+//! dmesg-style. Only messages at or below the console level are also printed right away.
+//!
+//! The default level is info, or the one picked with a `log-level-*` cargo feature.
+//! The kernel command line can change it: `log=debug,scheduler=trace,page=warn` sets the default
+//! and per-module levels, `loglevel=warn` the console level.
+use crate::{arch, println, spinlock::IrqSpinLock};
+use core::fmt::{self, Arguments, Write};
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use crate::ring_buffer::RingBuffer;
+
+const LOG_BUFFER_SIZE: usize = 16 * 1024;
+const MAX_MODULE_FILTERS: usize = 8;
+/// Prefix stripped from `module_path!()`, so filters and messages use `scheduler` instead of `oxiv_kernel::scheduler`.
+const CRATE_PREFIX: &str = "oxiv_kernel::";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Level {
+    Off = 0,
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+    Trace = 5,
+}
+
+impl Level {
+    fn parse(name: &str) -> Option<Level> {
+        match name {
+            "off" => Some(Level::Off),
+            "error" => Some(Level::Error),
+            "warn" => Some(Level::Warn),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            "trace" => Some(Level::Trace),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Level::Off => "OFF",
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+}
+
+/// Features add up across the dependency graph, so with several enabled the quietest one wins.
+const DEFAULT_LEVEL: Level = if cfg!(feature = "log-level-error") {
+    Level::Error
+} else if cfg!(feature = "log-level-warn") {
+    Level::Warn
+} else if cfg!(feature = "log-level-info") {
+    Level::Info
+} else if cfg!(feature = "log-level-debug") {
+    Level::Debug
+} else if cfg!(feature = "log-level-trace") {
+    Level::Trace
+} else {
+    Level::Info
+};
+
+/// Highest level any module logs at, to reject most messages without taking the lock.
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(DEFAULT_LEVEL as u8);
+static CONSOLE_LEVEL: AtomicU8 = AtomicU8::new(Level::Trace as u8);
+static FILTERS: IrqSpinLock<Filters> = IrqSpinLock::new(Filters::new());
+static LOG_BUFFER: IrqSpinLock<LogBuffer> = IrqSpinLock::new(LogBuffer::new());
+/// Messages go to the buffer and console this much at a time. The console is written with the buffer
+/// unlocked: it masks interrupts, and other harts would spin like that for as long as the UART takes.
+const CHUNK_SIZE: usize = 256;
+
+struct LogBuffer {
+    bytes: RingBuffer<LOG_BUFFER_SIZE>,
+    /// Bytes ever pushed, so `dump` can tell where it was after letting go of the lock.
+    written: u64,
+}
+
+impl LogBuffer {
+    const fn new() -> Self {
+        LogBuffer {
+            bytes: RingBuffer::new(),
+            written: 0,
+        }
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.bytes.push_overwrite(byte);
+        }
+        self.written += bytes.len() as u64;
+    }
+
+    /// Position of the oldest byte still in the buffer.
+    fn oldest(&self) -> u64 {
+        self.written - self.bytes.len() as u64
+    }
+}
+
+struct Filters {
+    default: Level,
+    modules: [(&'static str, Level); MAX_MODULE_FILTERS],
+    len: usize,
+}
+
+impl Filters {
+    const fn new() -> Self {
+        Filters {
+            default: DEFAULT_LEVEL,
+            modules: [("", Level::Off); MAX_MODULE_FILTERS],
+            len: 0,
+        }
+    }
+
+    /// The most specific filter wins, so `page_table` can differ from `page`.
+    fn level(&self, module: &str) -> Level {
+        self.modules[..self.len]
+            .iter()
+            .filter(|(name, _)| {
+                module
+                    .strip_prefix(name)
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+            })
+            .max_by_key(|(name, _)| name.len())
+            .map_or(self.default, |&(_, level)| level)
+    }
+
+    fn max_level(&self) -> Level {
+        self.modules[..self.len]
+            .iter()
+            .map(|&(_, level)| level)
+            .fold(self.default, Level::max)
+    }
+}
+
+/// Applies the `log=` and `loglevel=` options of the kernel command line.
+/// Unknown options are left for others, malformed ones are reported and skipped.
+pub fn init(cmdline: &'static str) {
+    let mut filters = FILTERS.lock();
+    for option in cmdline.split_whitespace() {
+        if let Some(level) = option.strip_prefix("loglevel=") {
+            match Level::parse(level) {
+                Some(level) => CONSOLE_LEVEL.store(level as u8, Ordering::Relaxed),
+                None => println!("log: unknown console level '{}'", level),
+            }
+            continue;
+        }
+        let Some(specs) = option.strip_prefix("log=") else {
+            continue;
+        };
+        for spec in specs.split(',') {
+            let (module, level) = match spec.split_once('=') {
+                Some((module, level)) => (Some(module), level),
+                None => (None, spec),
+            };
+            let Some(level) = Level::parse(level) else {
+                println!("log: unknown level in '{}'", spec);
+                continue;
+            };
+            match module {
+                None => filters.default = level,
+                Some(_) if filters.len == MAX_MODULE_FILTERS => {
+                    println!("log: too many module filters, ignoring '{}'", spec);
+                }
+                Some(module) => {
+                    let len = filters.len;
+                    filters.modules[len] = (module, level);
+                    filters.len += 1;
+                }
+            }
+        }
+    }
+    MAX_LEVEL.store(filters.max_level() as u8, Ordering::Relaxed);
+}
+
+/// Whether messages of `level` from `module` (a `module_path!()`) are logged at all.
+pub fn enabled(level: Level, module: &str) -> bool {
+    if level as u8 > MAX_LEVEL.load(Ordering::Relaxed) || level == Level::Off {
+        return false;
+    }
+    level <= FILTERS.lock().level(short_module(module))
+}
+
+/// Records a message, use the `error!` to `trace!` macros instead.
+pub fn log(level: Level, module: &str, args: Arguments) {
+    let now = arch::read_time();
+    let micros = now / (arch::TIMEBASE_FREQUENCY / 1_000_000);
+    let to_console = level as u8 <= CONSOLE_LEVEL.load(Ordering::Relaxed);
+
+    let mut writer = LogWriter {
+        chunk: [0; CHUNK_SIZE],
+        len: 0,
+        to_console,
+    };
+    let _ = writeln!(
+        writer,
+        "[{:>5}.{:06}] [{}] {:<5} {}: {}",
+        micros / 1_000_000,
+        micros % 1_000_000,
+        crate::cpu::current().hart_id(),
+        level.name(),
+        short_module(module),
+        args
+    );
+    writer.flush();
+}
+
+/// Prints every message still in the log buffer, a chunk at a time so the buffer isn't held while printing.
+/// Messages that come in meanwhile are not printed, and those pushed out of the buffer are skipped.
+pub fn dump() {
+    let (mut position, end) = {
+        let buffer = LOG_BUFFER.lock();
+        // Once the buffer wrapped the first line is cut off, so start at the next one
+        let skip = if buffer.bytes.is_full() {
+            buffer
+                .bytes
+                .iter()
+                .position(|byte| byte == b'\n')
+                .map_or(0, |i| i + 1)
+        } else {
+            0
+        };
+        (buffer.oldest() + skip as u64, buffer.written)
+    };
+    let mut chunk = [0u8; CHUNK_SIZE];
+    while position < end {
+        let len = {
+            let buffer = LOG_BUFFER.lock();
+            position = position.max(buffer.oldest());
+            if position >= end {
+                break;
+            }
+            let offset = (position - buffer.oldest()) as usize;
+            let available = (end - position) as usize;
+            let mut len = 0;
+            for byte in buffer
+                .bytes
+                .iter()
+                .skip(offset)
+                .take(available.min(CHUNK_SIZE))
+            {
+                chunk[len] = byte;
+                len += 1;
+            }
+            len
+        };
+        // Whole lines where possible, so a character is only cut off in very long ones
+        let len = match chunk[..len].iter().rposition(|&byte| byte == b'\n') {
+            Some(newline) => newline + 1,
+            None => len,
+        };
+        arch::console_write(&alloc::string::String::from_utf8_lossy(&chunk[..len]));
+        position += len as u64;
+    }
+}
+
+fn short_module(module: &str) -> &str {
+    match module.strip_prefix(CRATE_PREFIX) {
+        Some(module) => module,
+        None if module == "oxiv_kernel" => "kernel",
+        None => module,
+    }
+}
+
+/// Collects a message on the stack, handing it to the buffer and console a chunk at a time.
+struct LogWriter {
+    chunk: [u8; CHUNK_SIZE],
+    len: usize,
+    to_console: bool,
+}
+
+impl LogWriter {
+    fn flush(&mut self) {
+        let chunk = &self.chunk[..self.len];
+        LOG_BUFFER.lock().push(chunk);
+        if self.to_console {
+            // Only cut at character boundaries, see `write_str`
+            if let Ok(text) = core::str::from_utf8(chunk) {
+                arch::console_write(text);
+            }
+        }
+        self.len = 0;
+    }
+}
+
+impl fmt::Write for LogWriter {
+    fn write_str(&mut self, mut s: &str) -> fmt::Result {
+        while !s.is_empty() {
+            let mut len = s.len().min(CHUNK_SIZE - self.len);
+            while !s.is_char_boundary(len) {
+                len -= 1;
+            }
+            if len == 0 {
+                self.flush();
+                continue;
+            }
+            self.chunk[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+            self.len += len;
+            s = &s[len..];
+        }
+        Ok(())
+    }
+}
+
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => {
+        if $crate::log::enabled($level, module_path!()) {
+            $crate::log::log($level, module_path!(), format_args!($($arg)*));
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Error, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Warn, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Info, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Debug, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Trace, $($arg)*));
+}
@@ -0,0 +1,456 @@
+//! Kernel monitor: a small shell on the serial console for poking at the running kernel.
+use crate::address_space::Vma;
+use crate::drivers::uart;
+use crate::page_table::{EntryFlags, VirtualAddress};
+use crate::scheduler::{Scheduler, SCHEDULER};
+use crate::{arch, cpu, log, page, print, println};
+use alloc::{collections::vec_deque::VecDeque, string::String, vec::Vec};
+
+const PROMPT: &str = "oxiv> ";
+const HISTORY_SIZE: usize = 16;
+/// How long to sleep when there is no input, if we cannot block on the UART.
+const POLL_INTERVAL_MS: usize = 20;
+const DEFAULT_PEEK_WORDS: usize = 4;
+
+const CTRL_A: u8 = 0x01;
+const CTRL_C: u8 = 0x03;
+const CTRL_E: u8 = 0x05;
+const CTRL_U: u8 = 0x15;
+const BACKSPACE: u8 = 0x08;
+const TAB: u8 = b'\t';
+const ESCAPE: u8 = 0x1b;
+const DELETE: u8 = 0x7f;
+
+struct Command {
+    name: &'static str,
+    usage: &'static str,
+    run: fn(args: &[&str]),
+}
+
+static COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        usage: "help                  list the commands",
+        run: help,
+    },
+    Command {
+        name: "dmesg",
+        usage: "dmesg                 show the kernel log",
+        run: |_| log::dump(),
+    },
+    Command {
+        name: "mem",
+        usage: "mem                   show the page allocator",
+        run: mem,
+    },
+    Command {
+        name: "pt",
+        usage: "pt                    show the kernel page table",
+        run: pt,
+    },
+    Command {
+        name: "ps",
+        usage: "ps                    list the processes",
+        run: ps,
+    },
+    Command {
+        name: "maps",
+        usage: "maps <pid>            list the memory regions of a process",
+        run: maps,
+    },
+    Command {
+        name: "cpus",
+        usage: "cpus                  list the harts",
+        run: cpus,
+    },
+    Command {
+        name: "spawn",
+        usage: "spawn <program> [arg] start a user program",
+        run: spawn,
+    },
+    Command {
+        name: "kill",
+        usage: "kill <pid>            end a process",
+        run: kill,
+    },
+    Command {
+        name: "peek",
+        usage: "peek <addr> [words]   read kernel memory",
+        run: peek,
+    },
+    Command {
+        name: "poke",
+        usage: "poke <addr> <value>   write a word of kernel memory",
+        run: poke,
+    },
+    Command {
+        name: "trap",
+        usage: "trap stats            count the traps taken per cause",
+        run: trap,
+    },
+    Command {
+        name: "reboot",
+        usage: "reboot                restart the machine",
+        run: |_| arch::reboot(),
+    },
+    Command {
+        name: "shutdown",
+        usage: "shutdown              power off the machine",
+        run: |_| arch::shutdown(),
+    },
+];
+
+/// Entry point of the monitor process.
+pub fn run() {
+    println!("Kernel monitor ready, type 'help' for the commands");
+    let mut editor = LineEditor::new();
+    loop {
+        let line = editor.read_line();
+        let args: Vec<&str> = line.split_whitespace().collect();
+        let Some(&name) = args.first() else {
+            continue;
+        };
+        match COMMANDS.iter().find(|command| command.name == name) {
+            Some(command) => (command.run)(&args[1..]),
+            None => println!("Unknown command '{}', try 'help'", name),
+        }
+    }
+}
+
+fn help(_args: &[&str]) {
+    for command in COMMANDS {
+        println!("  {}", command.usage);
+    }
+}
+
+fn mem(_args: &[&str]) {
+    page::PAGE_ALLOCATOR.lock().print_page_allocations();
+}
+
+fn pt(_args: &[&str]) {
+    crate::ROOT_PAGE_TABLE.lock().print_entries(true);
+}
+
+fn ps(_args: &[&str]) {
+    // Collect first, so the scheduler isn't held while printing
+    let infos = SCHEDULER.lock().process_infos();
+    for info in infos {
+        println!("{}", info);
+    }
+}
+
+fn maps(args: &[&str]) {
+    let Some(pid) = args.first().and_then(|arg| arg.parse::<u32>().ok()) else {
+        println!("Usage: maps <pid>");
+        return;
+    };
+    // Taken out first, the memory lock can block and the scheduler lock cannot be held then
+    let Some(memory) = SCHEDULER.lock().memory_of(pid) else {
+        println!("No process {}", pid);
+        return;
+    };
+    // Copied first, so the process isn't held up while printing
+    let regions: Vec<Vma> = memory.lock().address_space.regions().cloned().collect();
+    for region in regions {
+        println!("  {}", region);
+    }
+}
+
+fn cpus(_args: &[&str]) {
+    for cpu in cpu::cpus() {
+        println!(
+            "  cpu {}: hart {}{}",
+            cpu.index(),
+            cpu.hart_id(),
+            if cpu.is_online() { "" } else { " (offline)" }
+        );
+    }
+}
+
+fn spawn(args: &[&str]) {
+    let Some(&name) = args.first() else {
+        println!("Usage: spawn <program> [arg]...");
+        return;
+    };
+    let Some((_, elf)) = crate::PROGRAMS.iter().find(|(program, _)| *program == name) else {
+        let names: Vec<&str> = crate::PROGRAMS
+            .iter()
+            .map(|(program, _)| *program)
+            .collect();
+        println!("No program '{}', there is {}", name, names.join(", "));
+        return;
+    };
+    let result = SCHEDULER
+        .lock()
+        .schedule_user_process(elf, args, &["OS=oxiv"]);
+    match result {
+        Ok(info) => println!("{}: {}", name, info),
+        Err(error) => println!("Could not start {}: {:?}", name, error),
+    }
+}
+
+fn kill(args: &[&str]) {
+    let Some(pid) = args.first().and_then(|arg| arg.parse::<u32>().ok()) else {
+        println!("Usage: kill <pid>");
+        return;
+    };
+    if pid == Scheduler::current_pid() {
+        println!("The monitor cannot kill itself");
+    } else if SCHEDULER.lock().kill(pid) {
+        println!("Killed process {}", pid);
+    } else {
+        println!("No process {} that can be killed", pid);
+    }
+}
+
+fn peek(args: &[&str]) {
+    let Some(address) = args.first().and_then(|arg| parse_number(arg)) else {
+        println!("Usage: peek <addr> [words]");
+        return;
+    };
+    let words = args
+        .get(1)
+        .and_then(|arg| parse_number(arg))
+        .unwrap_or(DEFAULT_PEEK_WORDS);
+    for i in 0..words {
+        let address = address + i * size_of::<usize>();
+        if !is_accessible(address, EntryFlags::Read) {
+            println!("{:#010x}: not mapped readable", address);
+            return;
+        }
+        let value = unsafe { (address as *const usize).read_volatile() };
+        println!("{:#010x}: {:#010x}", address, value);
+    }
+}
+
+fn poke(args: &[&str]) {
+    let (Some(address), Some(value)) = (
+        args.first().and_then(|arg| parse_number(arg)),
+        args.get(1).and_then(|arg| parse_number(arg)),
+    ) else {
+        println!("Usage: poke <addr> <value>");
+        return;
+    };
+    if !is_accessible(address, EntryFlags::Write) {
+        println!("{:#010x}: not mapped writable", address);
+        return;
+    }
+    unsafe { (address as *mut usize).write_volatile(value) };
+    println!("{:#010x}: {:#010x}", address, value);
+}
+
+fn trap(args: &[&str]) {
+    if args.first() != Some(&"stats") {
+        println!("Usage: trap stats");
+        return;
+    }
+    for (cause, count) in arch::trap_counts() {
+        println!("  {:>10} {}", count, cause);
+    }
+}
+
+/// Whether `address` is a word aligned kernel address mapped with `flags`, so touching it cannot fault.
+fn is_accessible(address: usize, flags: EntryFlags) -> bool {
+    address.is_multiple_of(size_of::<usize>())
+        && crate::ROOT_PAGE_TABLE
+            .lock()
+            .translate(&VirtualAddress(address), flags as usize)
+            .is_some()
+}
+
+/// Parses decimal, or hexadecimal with a `0x` prefix.
+fn parse_number(arg: &str) -> Option<usize> {
+    match arg.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => arg.parse().ok(),
+    }
+}
+
+/// Reads lines from the console with basic editing: cursor movement, history and completion of command names.
+struct LineEditor {
+    line: Vec<u8>,
+    cursor: usize,
+    history: VecDeque<String>,
+    /// Entry of the history being shown, if any.
+    history_index: Option<usize>,
+}
+
+impl LineEditor {
+    fn new() -> Self {
+        LineEditor {
+            line: Vec::new(),
+            cursor: 0,
+            history: VecDeque::with_capacity(HISTORY_SIZE),
+            history_index: None,
+        }
+    }
+
+    fn read_line(&mut self) -> String {
+        self.line.clear();
+        self.cursor = 0;
+        self.history_index = None;
+        print!("{}", PROMPT);
+        loop {
+            match read_byte() {
+                b'\r' | b'\n' => break,
+                CTRL_C => {
+                    println!("^C");
+                    self.line.clear();
+                    self.cursor = 0;
+                    print!("{}", PROMPT);
+                }
+                CTRL_U => self.set_line(String::new()),
+                CTRL_A => self.move_cursor(0),
+                CTRL_E => self.move_cursor(self.line.len()),
+                BACKSPACE | DELETE if self.cursor > 0 => {
+                    self.cursor -= 1;
+                    self.line.remove(self.cursor);
+                    self.refresh();
+                }
+                TAB => self.complete(),
+                ESCAPE => self.handle_escape(),
+                byte @ b' '..=b'~' => self.insert(byte),
+                _ => {}
+            }
+        }
+        println!();
+
+        let line = String::from_utf8_lossy(&self.line).into_owned();
+        if !line.trim().is_empty() && self.history.back() != Some(&line) {
+            if self.history.len() == HISTORY_SIZE {
+                self.history.pop_front();
+            }
+            self.history.push_back(line.clone());
+        }
+        line
+    }
+
+    /// Handles the ANSI sequences sent by the arrow, home, end and delete keys.
+    fn handle_escape(&mut self) {
+        if read_byte() != b'[' {
+            return;
+        }
+        match read_byte() {
+            b'A' => self.history_up(),
+            b'B' => self.history_down(),
+            b'C' => self.move_cursor((self.cursor + 1).min(self.line.len())),
+            b'D' => self.move_cursor(self.cursor.saturating_sub(1)),
+            b'H' => self.move_cursor(0),
+            b'F' => self.move_cursor(self.line.len()),
+            b'3' if read_byte() == b'~' && self.cursor < self.line.len() => {
+                self.line.remove(self.cursor);
+                self.refresh();
+            }
+            _ => {}
+        }
+    }
+
+    fn insert(&mut self, byte: u8) {
+        self.line.insert(self.cursor, byte);
+        self.cursor += 1;
+        if self.cursor == self.line.len() {
+            print!("{}", byte as char);
+        } else {
+            self.refresh();
+        }
+    }
+
+    fn move_cursor(&mut self, cursor: usize) {
+        self.cursor = cursor;
+        self.refresh();
+    }
+
+    fn set_line(&mut self, line: String) {
+        self.line = line.into_bytes();
+        self.cursor = self.line.len();
+        self.refresh();
+    }
+
+    fn history_up(&mut self) {
+        let index = match self.history_index {
+            None if self.history.is_empty() => return,
+            None => self.history.len() - 1,
+            Some(index) => index.saturating_sub(1),
+        };
+        self.history_index = Some(index);
+        self.set_line(self.history[index].clone());
+    }
+
+    fn history_down(&mut self) {
+        let Some(index) = self.history_index else {
+            return;
+        };
+        if index + 1 < self.history.len() {
+            self.history_index = Some(index + 1);
+            self.set_line(self.history[index + 1].clone());
+        } else {
+            self.history_index = None;
+            self.set_line(String::new());
+        }
+    }
+
+    /// Completes the command name before the cursor, or lists the candidates if that is ambiguous.
+    fn complete(&mut self) {
+        let prefix = &self.line[..self.cursor];
+        if prefix.contains(&b' ') {
+            return;
+        }
+        let candidates: Vec<&str> = COMMANDS
+            .iter()
+            .map(|command| command.name)
+            .filter(|name| name.as_bytes().starts_with(prefix))
+            .collect();
+        let Some(first) = candidates.first() else {
+            return;
+        };
+        if candidates.len() == 1 {
+            let mut completed = String::from(*first);
+            completed.push(' ');
+            completed.push_str(&String::from_utf8_lossy(&self.line[self.cursor..]));
+            self.line = completed.into_bytes();
+            self.cursor = first.len() + 1;
+            self.refresh();
+            return;
+        }
+        // Extend to what all candidates have in common
+        let common = candidates.iter().fold(first.len(), |len, name| {
+            first
+                .bytes()
+                .zip(name.bytes())
+                .take(len)
+                .take_while(|(a, b)| a == b)
+                .count()
+        });
+        if common > self.cursor {
+            let rest = self.line.split_off(self.cursor);
+            self.line = first.as_bytes()[..common].to_vec();
+            self.line.extend_from_slice(&rest);
+            self.cursor = common;
+            self.refresh();
+        } else {
+            println!();
+            println!("{}", candidates.join("  "));
+            self.refresh();
+        }
+    }
+
+    /// Redraws the prompt and line, and puts the terminal cursor back where ours is.
+    fn refresh(&self) {
+        print!("\r{}{}\x1b[K", PROMPT, String::from_utf8_lossy(&self.line));
+        let back = self.line.len() - self.cursor;
+        if back > 0 {
+            print!("\x1b[{}D", back);
+        }
+    }
+}
+
+fn read_byte() -> u8 {
+    loop {
+        if let Some(byte) = arch::console_read() {
+            return byte;
+        }
+        if !uart::wait_for_input() {
+            Scheduler::sleep(POLL_INTERVAL_MS);
+        }
+    }
+}
@@ -1,9 +1,15 @@
 // Heavily Inspired by Stephen Marz's blog post: https://osblog.stephenmarz.com/ch3.html
-use crate::arch::PAGE_ORDER;
-use crate::arch::PAGE_SIZE;
-use crate::{print, println, spinlock::SpinLock};
+// The buddy system itself follows the classic Knuth description, as also used by Linux.
+use crate::arch::{self, PAGE_ORDER, PAGE_SIZE};
+use crate::{print, println, spinlock::IrqSpinLock};
+use core::ops::Range;
 
-pub static PAGE_ALLOCATOR: SpinLock<PageAllocator> = SpinLock::new(PageAllocator::new());
+pub static PAGE_ALLOCATOR: IrqSpinLock<PageAllocator> = IrqSpinLock::new(PageAllocator::new());
+
+/// Blocks go up to 2^MAX_ORDER pages (64MiB).
+const MAX_ORDER: usize = 14;
+/// How many disjoint ranges a `MemoryMap` can hold.
+const MAX_RANGES: usize = 16;
 
 /// Aligns a value to the next multiple of the order.
 /// So, this is a little trick to make sure any address is aligned to a page boundary.
@@ -12,24 +18,115 @@ pub const fn align_val(val: usize, order: usize) -> usize {
     (val + order) & !order
 }
 
+/// Smallest order whose block holds `nr_of_pages` pages.
+fn order_for(nr_of_pages: usize) -> usize {
+    nr_of_pages.next_power_of_two().trailing_zeros() as usize
+}
+
+/// Usable physical memory, as page aligned ranges that don't overlap.
+pub struct MemoryMap {
+    ranges: [Range<usize>; MAX_RANGES],
+    len: usize,
+}
+
+impl MemoryMap {
+    pub const fn new() -> Self {
+        MemoryMap {
+            ranges: [const { 0..0 }; MAX_RANGES],
+            len: 0,
+        }
+    }
+
+    pub fn ranges(&self) -> &[Range<usize>] {
+        &self.ranges[..self.len]
+    }
+
+    /// Adds usable memory, shrunk to whole pages.
+    pub fn add(&mut self, range: Range<usize>) {
+        let start = align_val(range.start, PAGE_ORDER);
+        let end = range.end & !(PAGE_SIZE - 1);
+        if start < end {
+            self.push(start..end);
+        }
+    }
+
+    /// Takes out memory that is in use, grown to whole pages.
+    pub fn remove(&mut self, range: Range<usize>) {
+        let start = range.start & !(PAGE_SIZE - 1);
+        let end = align_val(range.end, PAGE_ORDER);
+        let old = core::mem::take(self);
+        for existing in old.ranges() {
+            if existing.end <= start || end <= existing.start {
+                self.push(existing.clone());
+                continue;
+            }
+            if existing.start < start {
+                self.push(existing.start..start);
+            }
+            if end < existing.end {
+                self.push(end..existing.end);
+            }
+        }
+    }
+
+    fn push(&mut self, range: Range<usize>) {
+        assert!(self.len < MAX_RANGES, "Too many memory ranges");
+        self.ranges[self.len] = range;
+        self.len += 1;
+    }
+}
+
+impl Default for MemoryMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Buddy allocator for physical pages.
+///
+/// Pages are handed out at their address in the direct map, ready to use by the kernel. Use `arch::virt_to_phys`
+/// where the physical address is needed, e.g. in a page table entry.
+/// Blocks of 2^order pages are aligned to their own size, in the direct map and so in physical memory too,
+/// so the buddy of a block is found by flipping bit `order` of its page number. Free blocks are kept in one doubly linked list
+/// per order, stored in the free pages themselves, which makes both alloc and dealloc O(MAX_ORDER).
+/// Every page between the lowest and highest usable address has a descriptor, so small holes
+/// (like the device tree) are simply never handed out.
 pub struct PageAllocator {
-    heap_start: usize,
+    descriptors: usize,
     alloc_start: usize,
+    alloc_end: usize,
     total_num_pages: usize,
+    free_lists: [*mut FreeBlock; MAX_ORDER + 1],
+}
+
+//Safety: The free lists only point into memory owned by the allocator, which is only reached through the lock
+unsafe impl Send for PageAllocator {}
+
+/// List node stored at the start of every free block.
+struct FreeBlock {
+    next: *mut FreeBlock,
+    prev: *mut FreeBlock,
 }
 
 #[repr(u8)]
+#[derive(Clone, Copy)]
 enum PageState {
+    /// Not the first page of a block
     Free = 0,
-    Taken = 1 << 0,
-    Last = 1 << 1,
+    /// First page of a block in the free lists
+    FreeHead = 1 << 6,
+    /// First page of a handed out block
+    Taken = 1 << 7,
 }
 
+/// One per page. Only the descriptor of the first page of a block is used, holding its state and order.
 #[repr(C)]
 struct PageDescriptor {
     flags: u8,
 }
 
+const ORDER_MASK: u8 = 0x1F;
+
 impl PageState {
     fn to_u8(self) -> u8 {
         self as u8
@@ -47,108 +144,176 @@ impl PageDescriptor {
         self.flags = PageState::Free.to_u8();
     }
 
-    fn add_flag(&mut self, flag: PageState) {
-        self.flags |= flag.to_u8();
+    fn set(&mut self, state: PageState, order: usize) {
+        self.flags = state.to_u8() | order as u8;
     }
 
-    fn is_free(&self) -> bool {
-        !self.is_taken()
+    fn is_free_head(&self) -> bool {
+        self.flags & PageState::FreeHead.to_u8() != 0
     }
 
     fn is_taken(&self) -> bool {
         self.flags & PageState::Taken.to_u8() != 0
     }
 
-    fn is_last(&self) -> bool {
-        self.flags & PageState::Last.to_u8() != 0
+    fn order(&self) -> usize {
+        (self.flags & ORDER_MASK) as usize
     }
 }
 
 impl PageAllocator {
     pub const fn new() -> Self {
         PageAllocator {
-            heap_start: 0,
+            descriptors: 0,
             total_num_pages: 0,
             alloc_start: 0,
+            alloc_end: 0,
+            free_lists: [core::ptr::null_mut(); MAX_ORDER + 1],
         }
     }
 
-    pub fn init(&mut self, heap_start: usize, heap_end: usize) {
-        let size = heap_end - heap_start;
-        let total_num_pages = size / PAGE_SIZE;
-        //Clear all pages
+    /// Takes over the physical `memory`, which has to lie in the direct map.
+    pub fn init(&mut self, memory: &MemoryMap) {
+        let mut direct_mapped = MemoryMap::new();
+        for range in memory.ranges() {
+            direct_mapped
+                .push(arch::phys_to_virt(range.start)..range.end + arch::DIRECT_MAP_OFFSET);
+        }
+        let ranges = direct_mapped.ranges();
+        let start = ranges.iter().map(|range| range.start).min().unwrap_or(0);
+        let end = ranges.iter().map(|range| range.end).max().unwrap_or(0);
+        let total_num_pages = (end - start) / PAGE_SIZE;
 
-        let alloc_start = align_val(
-            heap_start + (total_num_pages * size_of::<PageDescriptor>()),
-            PAGE_ORDER,
-        );
-        self.heap_start = heap_start;
+        // The descriptors take up the first pages of a range big enough to hold them
+        let descriptors_size = align_val(total_num_pages * size_of::<PageDescriptor>(), PAGE_ORDER);
+        let descriptors = ranges
+            .iter()
+            .find(|range| range.len() > descriptors_size)
+            .expect("No room for the page descriptors")
+            .start;
+        self.descriptors = descriptors;
         self.total_num_pages = total_num_pages;
-        self.alloc_start = alloc_start;
+        self.alloc_start = start;
+        self.alloc_end = end;
+        self.free_lists = [core::ptr::null_mut(); MAX_ORDER + 1];
 
-        for i in 0..self.total_num_pages {
-            let page = self.heap_start + i * PAGE_SIZE;
-            let pd = page as *mut PageDescriptor;
+        for i in 0..self.nr_of_alloc_pages() {
             unsafe {
-                pd.write(PageDescriptor::new());
+                self.descriptor(self.alloc_start + i * PAGE_SIZE)
+                    .write(PageDescriptor::new());
+            }
+        }
+
+        for range in ranges {
+            if range.start == descriptors {
+                self.free_range(range.start + descriptors_size, range.end);
+            } else {
+                self.free_range(range.start, range.end);
             }
         }
     }
 
-    pub fn alloc(&mut self, nr_of_pages: usize) -> *mut u8 {
-        let page = self.heap_start;
-        let mut pages_found = 0;
-        let mut pd = page as *mut PageDescriptor;
-        let mut found_index = (false, 0);
-        for i in 0..self.total_num_pages - nr_of_pages {
-            unsafe {
-                if (*pd).is_free() {
-                    pages_found += 1;
-                    if pages_found == nr_of_pages {
-                        found_index = (true, i - nr_of_pages + 1);
-                        break;
-                    }
-                } else {
-                    pages_found = 0;
-                }
-                pd = pd.add(1);
+    /// Hands out `start..end` as the biggest blocks that are aligned to their size and fit.
+    fn free_range(&mut self, start: usize, end: usize) {
+        let mut page = start;
+        while page < end {
+            let page_nr = page / PAGE_SIZE;
+            let mut order = MAX_ORDER;
+            while order > 0
+                && (!page_nr.is_multiple_of(1 << order) || page + (PAGE_SIZE << order) > end)
+            {
+                order -= 1;
             }
+            self.push_free(page, order);
+            page += PAGE_SIZE << order;
         }
-        if !found_index.0 {
+    }
+
+    /// Allocates at least `nr_of_pages` contiguous pages. Blocks come in powers of two,
+    /// so the rest of the block is handed out as well.
+    pub fn alloc(&mut self, nr_of_pages: usize) -> *mut u8 {
+        self.alloc_aligned(nr_of_pages, 1)
+    }
+
+    /// Allocates `nr_of_pages` contiguous pages, with the first one aligned to `align_pages` pages.
+    pub fn alloc_aligned(&mut self, nr_of_pages: usize, align_pages: usize) -> *mut u8 {
+        assert!(nr_of_pages > 0, "Allocating zero pages");
+        assert!(
+            align_pages.is_power_of_two(),
+            "Page alignment {} is not a power of two",
+            align_pages
+        );
+        // Blocks are aligned to their size, so a big enough block is aligned enough
+        let order = order_for(nr_of_pages).max(order_for(align_pages));
+        if order > MAX_ORDER {
             return core::ptr::null_mut();
         }
+
+        let Some(mut found_order) = (order..=MAX_ORDER).find(|&o| !self.free_lists[o].is_null())
+        else {
+            return core::ptr::null_mut();
+        };
+        let block = self.free_lists[found_order] as usize;
+        self.remove_free(block, found_order);
+
+        // Split off the upper halves until the block has the right size
+        while found_order > order {
+            found_order -= 1;
+            self.push_free(block + (PAGE_SIZE << found_order), found_order);
+        }
         unsafe {
-            (*pd).add_flag(PageState::Last);
-            pd = pd.sub(nr_of_pages);
-            for i in 0..=nr_of_pages {
-                (*pd.add(i)).add_flag(PageState::Taken);
-            }
+            (*self.descriptor(block)).set(PageState::Taken, order);
         }
-
-        //The function needs to return the address of the first page, not the descriptor
-        (self.alloc_start + found_index.1 * PAGE_SIZE) as *mut u8
+        block as *mut u8
     }
 
     pub fn dealloc(&mut self, page: *mut u8) {
+        let mut block = page as usize;
         //Check if the page is within the bounds of the allocator
-        let addr = self.heap_start + (page as usize - self.alloc_start) / PAGE_SIZE;
-
-        if addr < self.heap_start || addr >= self.alloc_start {
-            panic!("Page {:#x} is not within the bounds of the allocator. Calculated descriptor was {:#x}", page as usize, addr);
+        if block < self.alloc_start || block >= self.alloc_end || !block.is_multiple_of(PAGE_SIZE) {
+            panic!(
+                "Page {:#x} is not within the bounds of the allocator",
+                block
+            );
         }
 
-        //Clear all taken descriptors that are not marked as last
-        let mut pd = addr as *mut PageDescriptor;
-        unsafe {
-            while !(*pd).is_last() && (*pd).is_taken() {
-                (*pd).clear();
-                pd = pd.add(1);
-            }
-            //Guard against deallocating a page that is not marked as last since that indicates a double frees
-            assert!((*pd).is_last(), "Page is not marked as last");
-            //Clear the last page
+        let mut order = unsafe {
+            let pd = self.descriptor(block);
+            //Guard against freeing the middle of a block or a block that is already free, which indicates a double free
+            assert!(
+                (*pd).is_taken(),
+                "Page {:#x} is not the start of an allocated block",
+                block
+            );
+            let order = (*pd).order();
             (*pd).clear();
+            order
+        };
+        assert!(
+            (block / PAGE_SIZE).is_multiple_of(1 << order),
+            "Page {:#x} is not aligned to its order {}",
+            block,
+            order
+        );
+
+        // Merge with the buddy for as long as it is free as a whole
+        while order < MAX_ORDER {
+            let buddy = ((block / PAGE_SIZE) ^ (1 << order)) * PAGE_SIZE;
+            if buddy < self.alloc_start || buddy + (PAGE_SIZE << order) > self.alloc_end {
+                break;
+            }
+            let buddy_free = unsafe {
+                let pd = self.descriptor(buddy);
+                (*pd).is_free_head() && (*pd).order() == order
+            };
+            if !buddy_free {
+                break;
+            }
+            self.remove_free(buddy, order);
+            block = block.min(buddy);
+            order += 1;
         }
+        self.push_free(block, order);
     }
 
     pub fn zero_alloc(&mut self, pages: usize) -> *mut u8 {
@@ -164,27 +329,75 @@ impl PageAllocator {
 
     pub fn print_page_allocations(&self) {
         println!(
-            "PageAllocator: heap_start: {:#x}, alloc_start: {:#x}, total_num_pages: {}",
-            self.heap_start, self.alloc_start, self.total_num_pages
+            "PageAllocator: descriptors: {:#x}, memory: {:#x} -> {:#x}, total_num_pages: {}",
+            self.descriptors, self.alloc_start, self.alloc_end, self.total_num_pages
         );
-        let mut in_block = false;
-        for i in 0..self.total_num_pages {
-            let pd = (self.heap_start + i * size_of::<PageDescriptor>()) as *const PageDescriptor;
+        print!("Free blocks per order:");
+        for order in 0..=MAX_ORDER {
+            let mut count = 0;
+            let mut node = self.free_lists[order];
+            while !node.is_null() {
+                count += 1;
+                node = unsafe { (*node).next };
+            }
+            print!(" {}", count);
+        }
+        println!();
+        for i in 0..self.nr_of_alloc_pages() {
+            let page = self.alloc_start + i * PAGE_SIZE;
+            let pd = self.descriptor(page);
             unsafe {
                 if (*pd).is_taken() {
-                    if !in_block {
-                        print!("Block start: ");
-                        in_block = true;
-                    }
-                    print!("{:#x} ", self.heap_start + i * PAGE_SIZE);
-                    if (*pd).is_last() {
-                        println!("Done");
-                        in_block = false;
-                    }
+                    println!(
+                        "Block start: {:#x} ({} pages)",
+                        page,
+                        1usize << (*pd).order()
+                    );
                 }
             }
         }
     }
+
+    fn nr_of_alloc_pages(&self) -> usize {
+        (self.alloc_end - self.alloc_start) / PAGE_SIZE
+    }
+
+    fn descriptor(&self, page: usize) -> *mut PageDescriptor {
+        let index = (page - self.alloc_start) / PAGE_SIZE;
+        (self.descriptors + index * size_of::<PageDescriptor>()) as *mut PageDescriptor
+    }
+
+    fn push_free(&mut self, block: usize, order: usize) {
+        let node = block as *mut FreeBlock;
+        let head = self.free_lists[order];
+        unsafe {
+            node.write(FreeBlock {
+                next: head,
+                prev: core::ptr::null_mut(),
+            });
+            if !head.is_null() {
+                (*head).prev = node;
+            }
+            (*self.descriptor(block)).set(PageState::FreeHead, order);
+        }
+        self.free_lists[order] = node;
+    }
+
+    fn remove_free(&mut self, block: usize, order: usize) {
+        let node = block as *mut FreeBlock;
+        unsafe {
+            let FreeBlock { next, prev } = node.read();
+            if prev.is_null() {
+                self.free_lists[order] = next;
+            } else {
+                (*prev).next = next;
+            }
+            if !next.is_null() {
+                (*next).prev = prev;
+            }
+            (*self.descriptor(block)).clear();
+        }
+    }
 }
 
 //To satisfy clippy
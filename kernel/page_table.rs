@@ -1,11 +1,16 @@
-use crate::arch::PAGE_SIZE;
+use crate::arch::{self, PAGE_SIZE};
 use crate::page;
-use crate::println;
+use crate::{error, println};
+use alloc::boxed::Box;
 
 //See SV32 RISC-V Privileged ISA document
 const ENTRIES_PER_TABLE: usize = 1024; // 2^10 entries per table
+/// What a single level 1 entry covers, and so the size of a megapage: a leaf at level 1.
+pub const MEGAPAGE_SIZE: usize = ENTRIES_PER_TABLE * PAGE_SIZE;
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct VirtualAddress(pub usize);
-pub struct PhysicalAddress(u64);
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PhysicalAddress(pub u64);
 impl VirtualAddress {
     fn as_usize(&self) -> usize {
         self.0
@@ -26,7 +31,11 @@ impl VirtualAddress {
     }
 
     fn is_aligned(&self) -> bool {
-        self.0 % PAGE_SIZE == 0
+        self.0.is_multiple_of(PAGE_SIZE)
+    }
+
+    fn is_megapage_aligned(&self) -> bool {
+        self.0.is_multiple_of(MEGAPAGE_SIZE)
     }
 
     pub fn with_offset(&self, offset: usize) -> VirtualAddress {
@@ -39,13 +48,17 @@ impl PhysicalAddress {
         self.0
     }
 
-    fn to_ppn(&self) -> u32 {
+    fn to_ppn(self) -> u32 {
         let to_34 = self.0 & 0x3fffff000; // 34 bit
         (to_34 >> 2) as u32
     }
 
     fn is_aligned(&self) -> bool {
-        self.0 % PAGE_SIZE as u64 == 0
+        self.0.is_multiple_of(PAGE_SIZE as u64)
+    }
+
+    fn is_megapage_aligned(&self) -> bool {
+        self.0.is_multiple_of(MEGAPAGE_SIZE as u64)
     }
 
     pub fn with_offset(&self, offset: u64) -> PhysicalAddress {
@@ -66,6 +79,26 @@ pub enum EntryFlags {
     Dirty = 1 << 7,
 }
 
+/// Why `PageTable::map` refused a mapping, or `PageTable::unmap_range` could not finish.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError {
+    /// The page is mapped already. It has to be unmapped first, so nothing is replaced by accident.
+    AlreadyMapped,
+    /// There was no page for a new level 0 table.
+    OutOfMemory,
+}
+
+/// A leaf entry, as found by `PageTable::walk`.
+#[derive(Debug, Clone, Copy)]
+pub struct Mapping {
+    /// Start of the physical page.
+    pub phys_address: PhysicalAddress,
+    /// `PAGE_SIZE`, or `MEGAPAGE_SIZE` for a megapage.
+    pub size: usize,
+    /// All flag bits of the entry, see `EntryFlags`. Without `Valid` for a page all access was taken from.
+    pub flags: usize,
+}
+
 #[repr(C)]
 #[repr(align(4096))]
 pub struct PageTable {
@@ -80,10 +113,26 @@ impl Entry {
     fn is_valid(&self) -> bool {
         self.0 & EntryFlags::Valid as usize != 0
     }
+    /// Whether this level 0 entry maps a page. That includes pages all access was taken from,
+    /// which are not valid for the hardware but still own their page, see `PageTable::set_flags`.
+    fn is_mapped(&self) -> bool {
+        self.0 != 0
+    }
     fn get_phys_address(&self) -> PhysicalAddress {
         PhysicalAddress(((self.0 & 0xfffffc00) as u64) << 2)
     }
 
+    /// Entry pointing to the level 0 `table`, which came from the page allocator.
+    fn branch(table: *mut PageTable) -> Entry {
+        let phys_address = arch::virt_to_phys(table as usize);
+        Entry((phys_address >> 12) << 10 | EntryFlags::Valid as usize)
+    }
+
+    /// The level 0 table a branch points to, reached through the direct map.
+    fn table(&self) -> *mut PageTable {
+        arch::phys_to_virt(self.get_phys_address().0 as usize) as *mut PageTable
+    }
+
     fn is_leaf(&self) -> bool {
         //A entry is a leaf if it has Read Write or Execute set
         self.0
@@ -107,12 +156,13 @@ impl PageTable {
         }
     }
 
+    /// Maps the page at `virt_address` to the one at `phys_address`, failing if it is mapped already.
     pub fn map(
         &mut self,
         virt_address: VirtualAddress,
         phys_address: PhysicalAddress,
         flags: usize,
-    ) {
+    ) -> Result<(), MapError> {
         //Check address alignment
         assert!(
             virt_address.is_aligned(),
@@ -126,48 +176,291 @@ impl PageTable {
         );
 
         let level1 = &mut self.entries[virt_address.vpn1()];
-        let new_table = if !level1.is_valid() {
+        if level1.is_valid() && level1.is_leaf() {
+            return Err(MapError::AlreadyMapped);
+        }
+        let table = if !level1.is_valid() {
             // Allocate a new page table
-            let new_table: *mut u8 = page::PAGE_ALLOCATOR.lock().zero_alloc(1);
-            level1.0 = (new_table as usize >> 12) << 10 | EntryFlags::Valid as usize;
+            let new_table: *mut PageTable = page::PAGE_ALLOCATOR.lock().zero_alloc(1).cast();
+            if new_table.is_null() {
+                return Err(MapError::OutOfMemory);
+            }
+            *level1 = Entry::branch(new_table);
             new_table
         } else {
             // Get the existing page table from the level 1 entry
-            (level1.get_phys_address().0) as *mut u8
+            level1.table()
+        };
+        let leaf = unsafe { &mut (*table).entries[virt_address.vpn0()] };
+        if leaf.is_mapped() {
+            return Err(MapError::AlreadyMapped);
+        }
+        leaf.0 = phys_address.to_ppn() as usize | flags | EntryFlags::Valid as usize;
+        // A hart may cache that a page is not mapped too
+        arch::flush_page(virt_address.as_usize());
+        Ok(())
+    }
+
+    /// Maps the 4 MiB at `virt_address` to the 4 MiB at `phys_address` with a single level 1 entry.
+    /// Fails if any of it is mapped already.
+    pub fn map_megapage(
+        &mut self,
+        virt_address: VirtualAddress,
+        phys_address: PhysicalAddress,
+        flags: usize,
+    ) -> Result<(), MapError> {
+        assert!(
+            virt_address.is_megapage_aligned(),
+            "virt_address is not megapage aligned: {:#x}",
+            virt_address.as_usize()
+        );
+        assert!(
+            phys_address.is_megapage_aligned(),
+            "phys_address is not megapage aligned: {:#x}",
+            phys_address.as_u64()
+        );
+        let level1 = &mut self.entries[virt_address.vpn1()];
+        if level1.is_valid() {
+            return Err(MapError::AlreadyMapped);
+        }
+        level1.0 = phys_address.to_ppn() as usize | flags | EntryFlags::Valid as usize;
+        arch::flush_page(virt_address.as_usize());
+        Ok(())
+    }
+
+    /// Replaces the flags of the mapped page at `virt_address`, returning whether it did.
+    /// In a megapage, that changes all of it.
+    /// Without Read, Write or Execute the entry would point to a table, so then the page is made invalid
+    /// instead, but stays mapped until it is unmapped or given access again.
+    /// A megapage cannot be made invalid like that, the entry would no longer be there for `map` to see.
+    pub fn set_flags(&mut self, virt_address: &VirtualAddress, flags: usize) -> bool {
+        let access =
+            EntryFlags::Read as usize | EntryFlags::Write as usize | EntryFlags::Execute as usize;
+        let valid = match flags & access {
+            0 => 0,
+            _ => EntryFlags::Valid as usize,
+        };
+        let level1_leaf = {
+            let level1 = &self.entries[virt_address.vpn1()];
+            level1.is_valid() && level1.is_leaf()
         };
-        unsafe {
-            (*(new_table as *mut PageTable)).entries[virt_address.vpn0()].0 =
-                phys_address.to_ppn() as usize | flags | EntryFlags::Valid as usize;
+        if valid == 0 && level1_leaf {
+            return false;
         }
+        let Some(leaf) = self.leaf_mut(virt_address) else {
+            return false;
+        };
+        leaf.0 = (leaf.0 & !0x3FF) | (flags & !(EntryFlags::Valid as usize)) | valid;
+        arch::flush_page(virt_address.as_usize());
+        true
     }
 
-    pub fn unmap(&mut self) {
-        for entry in self.entries.iter_mut() {
-            if entry.is_valid() && entry.is_branch() {
-                let table = entry.get_phys_address().0 as *mut PageTable;
-                page::PAGE_ALLOCATOR.lock().dealloc(table as *mut u8);
+    /// Removes the mapping of the page at `virt_address`, returning the physical page it mapped to.
+    /// Fails if it is part of a megapage that could not be split, which then stays mapped.
+    pub fn unmap_page(
+        &mut self,
+        virt_address: &VirtualAddress,
+    ) -> Result<Option<PhysicalAddress>, MapError> {
+        let mut unmapped = None;
+        self.unmap_range(
+            *virt_address,
+            virt_address.with_offset(PAGE_SIZE),
+            |_, phys_address| unmapped = Some(phys_address),
+        )?;
+        Ok(unmapped)
+    }
+
+    /// Removes every mapping in [start, end), calling `f` with each page that was mapped and where it mapped to.
+    /// Level 0 tables left empty are freed. That must not happen to tables shared with process tables,
+    /// see `new_process`, so the kernel does not unmap its own memory.
+    /// Unmapping part of a megapage needs a new table. Without memory for it this stops there,
+    /// leaving the megapage and everything after it mapped.
+    pub fn unmap_range(
+        &mut self,
+        start: VirtualAddress,
+        end: VirtualAddress,
+        mut f: impl FnMut(VirtualAddress, PhysicalAddress),
+    ) -> Result<(), MapError> {
+        assert!(
+            start.is_aligned(),
+            "start is not aligned: {:#x}",
+            start.as_usize()
+        );
+        let mut address = start.as_usize();
+        while address < end.as_usize() {
+            // The part of the range that this level 1 entry covers
+            let chunk_end = (address - address % MEGAPAGE_SIZE)
+                .checked_add(MEGAPAGE_SIZE)
+                .map_or(end.as_usize(), |next| next.min(end.as_usize()));
+            let level1 = &mut self.entries[VirtualAddress(address).vpn1()];
+            if level1.is_valid() && level1.is_leaf() {
+                if address.is_multiple_of(MEGAPAGE_SIZE) && chunk_end - address == MEGAPAGE_SIZE {
+                    let phys_address = level1.get_phys_address();
+                    level1.0 = 0;
+                    arch::flush_page(address);
+                    f(VirtualAddress(address), phys_address);
+                    address = chunk_end;
+                    continue;
+                }
+                // Only part of it goes, so the rest has to become 4 KiB pages
+                Self::split_megapage(level1)?;
+            }
+            if level1.is_valid() && level1.is_branch() {
+                let table = unsafe { &mut *level1.table() };
+                for page in (address..chunk_end).step_by(PAGE_SIZE) {
+                    let leaf = &mut table.entries[VirtualAddress(page).vpn0()];
+                    if leaf.is_mapped() {
+                        let phys_address = leaf.get_phys_address();
+                        leaf.0 = 0;
+                        arch::flush_page(page);
+                        f(VirtualAddress(page), phys_address);
+                    }
+                }
+                if table.entries.iter().all(|entry| !entry.is_mapped()) {
+                    page::PAGE_ALLOCATOR
+                        .lock()
+                        .dealloc(table as *mut PageTable as *mut u8);
+                    level1.0 = 0;
+                    // Flushing by address only covers leaf entries
+                    arch::flush_all();
+                }
             }
+            address = chunk_end;
+        }
+        Ok(())
+    }
+
+    /// Replaces the megapage in `level1` with a level 0 table mapping the same memory in 4 KiB pages.
+    fn split_megapage(level1: &mut Entry) -> Result<(), MapError> {
+        let table: *mut PageTable = page::PAGE_ALLOCATOR.lock().zero_alloc(1).cast();
+        if table.is_null() {
+            return Err(MapError::OutOfMemory);
+        }
+        let base = level1.get_phys_address();
+        let flags = level1.0 & 0x3FF;
+        for (i, entry) in unsafe { (*table).entries.iter_mut() }.enumerate() {
+            entry.0 = base.with_offset((i * PAGE_SIZE) as u64).to_ppn() as usize | flags;
+        }
+        *level1 = Entry::branch(table);
+        arch::flush_all();
+        Ok(())
+    }
+
+    /// Finds the leaf entry for `virt_address`, if it is mapped.
+    pub fn walk(&self, virt_address: &VirtualAddress) -> Option<Mapping> {
+        let level1 = &self.entries[virt_address.vpn1()];
+        if !level1.is_valid() {
+            return None;
+        }
+        if level1.is_leaf() {
+            return Some(Mapping {
+                phys_address: level1.get_phys_address(),
+                size: MEGAPAGE_SIZE,
+                flags: level1.0 & 0x3FF,
+            });
+        }
+        let leaf = unsafe { (*level1.table()).entries[virt_address.vpn0()] };
+        leaf.is_mapped().then_some(Mapping {
+            phys_address: leaf.get_phys_address(),
+            size: PAGE_SIZE,
+            flags: leaf.0 & 0x3FF,
+        })
+    }
+
+    /// Returns the physical address `virt_address` maps to, if it is mapped with at least `flags`.
+    pub fn translate(
+        &self,
+        virt_address: &VirtualAddress,
+        flags: usize,
+    ) -> Option<PhysicalAddress> {
+        let mapping = self.walk(virt_address)?;
+        if mapping.flags & flags != flags {
+            return None;
+        }
+        Some(
+            mapping
+                .phys_address
+                .with_offset((virt_address.as_usize() % mapping.size) as u64),
+        )
+    }
+
+    fn leaf_mut(&mut self, virt_address: &VirtualAddress) -> Option<&mut Entry> {
+        let level1 = &mut self.entries[virt_address.vpn1()];
+        if !level1.is_valid() {
+            return None;
+        }
+        if level1.is_leaf() {
+            return Some(level1);
+        }
+        let leaf = unsafe { &mut (*level1.table()).entries[virt_address.vpn0()] };
+        leaf.is_mapped().then_some(leaf)
+    }
+
+    /// Creates a root table for a process that shares the kernel's mappings.
+    /// Only the top level is copied, so both tables point to the same level 0 tables for kernel space.
+    /// This means the kernel has to create all of its level 1 entries before the first process exists.
+    pub fn new_process(kernel: &PageTable) -> Box<PageTable> {
+        //Safety: An all-zero table is a valid, empty table
+        let mut table = unsafe { Box::<PageTable>::new_zeroed().assume_init() };
+        table.entries = kernel.entries;
+        table
+    }
+
+    /// Frees every level 0 table this process table allocated itself, i.e. the ones not shared with `kernel`.
+    /// The pages mapped in them are not freed, that is up to whoever mapped them. See `AddressSpace`.
+    pub fn free_process_tables(&mut self, kernel: &PageTable) {
+        let mut allocator = page::PAGE_ALLOCATOR.lock();
+        for (entry, kernel_entry) in self.entries.iter_mut().zip(kernel.entries.iter()) {
+            if !entry.is_valid() || entry.0 == kernel_entry.0 {
+                continue;
+            }
+            assert!(entry.is_branch(), "Unexpected leaf at level 1");
+            allocator.dealloc(entry.table() as *mut u8);
             entry.0 = 0;
         }
     }
 
     //Todo: Should this be here? Or in kernel start? If here, whe should make it kernel specific
-    pub fn map_kernel_range(&mut self, start: VirtualAddress, end: VirtualAddress, flags: usize) {
-        if !start.is_aligned() {
-            println!("Start address is not aligned");
+    /// Maps [start, end) to the same amount of physical memory at `phys_start`,
+    /// with megapages where a whole aligned 4 MiB fits and is not in use yet.
+    pub fn map_kernel_range(
+        &mut self,
+        start: VirtualAddress,
+        end: VirtualAddress,
+        phys_start: PhysicalAddress,
+        flags: usize,
+    ) {
+        if !start.is_aligned() || !phys_start.is_aligned() {
+            error!(
+                "Start address {:#x} -> {:#x} is not aligned",
+                start.as_usize(),
+                phys_start.as_u64()
+            );
             return;
         }
 
-        let start = start.as_usize();
         let aligned_end = page::align_val(end.as_usize(), 12);
-
-        let num_pages = (aligned_end - start) / PAGE_SIZE;
-        for i in 0..num_pages {
-            self.map(
-                VirtualAddress(start).with_offset(i * PAGE_SIZE),
-                PhysicalAddress(start as u64).with_offset((i * PAGE_SIZE) as u64),
-                flags,
-            );
+        let mut virt_address = start;
+        while virt_address.as_usize() < aligned_end {
+            let offset = virt_address.as_usize() - start.as_usize();
+            let phys_address = phys_start.with_offset(offset as u64);
+            let use_megapage = virt_address.is_megapage_aligned()
+                && phys_address.is_megapage_aligned()
+                && aligned_end - virt_address.as_usize() >= MEGAPAGE_SIZE
+                && !self.entries[virt_address.vpn1()].is_valid();
+            let (result, size) = if use_megapage {
+                (
+                    self.map_megapage(virt_address, phys_address, flags),
+                    MEGAPAGE_SIZE,
+                )
+            } else {
+                (self.map(virt_address, phys_address, flags), PAGE_SIZE)
+            };
+            if let Err(err) = result {
+                error!("Cannot map {:#x}: {:?}", virt_address.as_usize(), err);
+                return;
+            }
+            virt_address = virt_address.with_offset(size);
         }
     }
 
@@ -176,6 +469,12 @@ impl PageTable {
     }
 
     fn print_entries_inner(&self, full: bool, prefix: &str) {
+        // Only the root is printed without a prefix, and a leaf there is a megapage
+        let leaf_kind = if prefix.is_empty() {
+            "Megapage"
+        } else {
+            "Leaf"
+        };
         for (i, entry) in self.entries.iter().enumerate() {
             if entry.is_valid() {
                 //Print the entry, but also the flags of the entry as binary
@@ -183,7 +482,7 @@ impl PageTable {
                     "{}Entry {} ({})=> Val: {:#x}, Phys: {:#x} Flags: {:0>10b}",
                     prefix,
                     i,
-                    if entry.is_leaf() { "Leaf" } else { "Branch" },
+                    if entry.is_leaf() { leaf_kind } else { "Branch" },
                     entry.0,
                     entry.get_phys_address().0,
                     entry.0 & 0x3FF
@@ -191,7 +490,7 @@ impl PageTable {
 
                 if full && entry.is_branch() {
                     unsafe {
-                        let table = entry.get_phys_address().0 as *mut PageTable;
+                        let table = entry.table();
                         (*table).print_entries_inner(full, "\t");
                     }
                 }
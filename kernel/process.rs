@@ -1,4 +1,44 @@
-use alloc::boxed::Box;
+use crate::address_space::{Access, AddressSpace, Backing, VmaError};
+use crate::arch::{self, Satp, PAGE_ORDER, PAGE_SIZE};
+use crate::elf::{self, Elf, ElfError, Segment};
+use crate::page;
+use crate::page_table::EntryFlags;
+use crate::sync::Mutex;
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use oxiv_abi::SyscallError;
+
+/// User programs are loaded at this address.
+pub const USER_BASE: usize = 0x2000_0000;
+/// The user stack grows down from here, at the end of the user part of the address space.
+pub const USER_STACK_TOP: usize = arch::USER_END;
+/// Mapped up front, for argv and envp.
+pub const USER_STACK_PAGES: usize = 4;
+const USER_STACK_BOTTOM: usize = USER_STACK_TOP - USER_STACK_PAGES * PAGE_SIZE;
+/// The stack can grow down to this many pages, the ones below `USER_STACK_BOTTOM` are backed on first touch.
+pub const USER_STACK_MAX_PAGES: usize = 64;
+const USER_STACK_LIMIT: usize = USER_STACK_TOP - USER_STACK_MAX_PAGES * PAGE_SIZE;
+/// Most the heap can grow to with `sbrk`. It starts at the first page after the program.
+pub const USER_HEAP_MAX_PAGES: usize = 256;
+const USER_RW: usize =
+    EntryFlags::Read as usize | EntryFlags::Write as usize | EntryFlags::User as usize;
+
+#[derive(Debug)]
+pub enum LoadError {
+    Elf(ElfError),
+    /// A segment does not fit between `USER_BASE` and the user stack.
+    OutsideUserSpace,
+    /// Two segments share a page.
+    OverlappingSegments,
+    /// argv and envp do not fit on the user stack.
+    ArgumentsTooLong,
+    OutOfMemory,
+}
+
+impl From<ElfError> for LoadError {
+    fn from(error: ElfError) -> Self {
+        LoadError::Elf(error)
+    }
+}
 
 #[repr(C, align(16))]
 #[derive(Clone, Default)]
@@ -20,21 +60,249 @@ pub struct CpuContext {
 }
 
 #[repr(C)]
-#[derive(Clone)]
 pub struct Process {
     pub pid: u32,
     pub state: ProcessState,
     pub context: CpuContext,
     pub kernel_stack: Box<[u8; 8192]>, //We allocate, but don't use directly. Used via pointer/assembly magic.
+    /// Locked on its own, so faults and copies don't hold up the scheduler, see `Scheduler::with_current_memory`.
+    pub memory: Arc<Mutex<UserMemory>>,
+    /// Of the page table in `memory`, which stays the same for the life of the process.
+    satp: Satp,
+    pub user_entry: usize,
+    pub user_stack: usize,
 }
 impl Default for Process {
     fn default() -> Self {
+        Self::new(0, ProcessState::Unused)
+    }
+}
+
+impl Process {
+    pub fn new(pid: u32, state: ProcessState) -> Self {
+        let memory = UserMemory {
+            address_space: AddressSpace::new(),
+            heap_end: 0,
+            heap_limit: 0,
+        };
         Self {
-            pid: 0,
-            state: ProcessState::Unused,
+            pid,
+            state,
             kernel_stack: Box::new([0; 8192]),
             context: CpuContext::default(),
+            satp: memory.address_space.satp(),
+            memory: Arc::new(Mutex::new(memory)),
+            user_entry: 0,
+            user_stack: 0,
+        }
+    }
+
+    pub fn is_user(&self) -> bool {
+        self.user_entry != 0
+    }
+
+    pub fn satp(&self) -> Satp {
+        self.satp
+    }
+
+    /// Gives the process its own page table with the executable `elf` loaded into it,
+    /// and a stack below `USER_STACK_TOP` holding `argv` and `envp`.
+    /// The heap starts out empty right after the program, the stack can grow down to `USER_STACK_LIMIT`.
+    pub fn load_elf(
+        &mut self,
+        elf: &'static [u8],
+        argv: &[&str],
+        envp: &[&str],
+    ) -> Result<(), LoadError> {
+        let memory = Arc::get_mut(&mut self.memory)
+            .expect("Nobody else has the memory of a process that is being loaded")
+            .get_mut();
+        let elf = Elf::parse(elf)?;
+        // Everything mapped so far is freed with the process if loading fails
+        let mut program_end = USER_BASE;
+        for segment in elf.segments() {
+            memory.load_segment(&segment)?;
+            program_end = program_end.max(segment.virt_address + segment.mem_size);
+        }
+        self.user_stack = memory.setup_user_stack(argv, envp)?;
+        self.user_entry = elf.entry;
+        memory.heap_end = page::align_val(program_end, PAGE_ORDER);
+        memory.heap_limit =
+            (memory.heap_end + USER_HEAP_MAX_PAGES * PAGE_SIZE).min(USER_STACK_LIMIT);
+        Ok(())
+    }
+}
+
+/// The user half of a process together with its heap.
+pub struct UserMemory {
+    pub address_space: AddressSpace,
+    /// End of the heap as `sbrk` sees it. The heap region itself ends at the next page boundary.
+    heap_end: usize,
+    /// How far `sbrk` can move the end of the heap.
+    heap_limit: usize,
+}
+
+impl UserMemory {
+    /// Moves the end of the heap up by `increment` bytes and returns the old end.
+    /// Nothing is mapped here, the pages are filled in as they are touched. The heap never shrinks.
+    pub fn sbrk(&mut self, increment: usize) -> Result<usize, SyscallError> {
+        let old_end = self.heap_end;
+        let new_end = old_end
+            .checked_add(increment)
+            .filter(|&end| end <= self.heap_limit)
+            .ok_or(SyscallError::InvalidArgument)?;
+        let region_end = page::align_val(old_end, PAGE_ORDER);
+        let new_region_end = page::align_val(new_end, PAGE_ORDER);
+        if new_region_end > region_end {
+            // Joins the heap region mapped by earlier calls
+            self.address_space
+                .map_region(region_end, new_region_end, USER_RW, Backing::Anonymous)
+                .map_err(|_| SyscallError::InvalidArgument)?;
+        }
+        self.heap_end = new_end;
+        Ok(old_end)
+    }
+
+    /// Maps `segment` as a private copy of its data in the executable, with the rest zeroed.
+    fn load_segment(&mut self, segment: &Segment<'static>) -> Result<(), LoadError> {
+        let start = segment.virt_address;
+        let end = start + segment.mem_size;
+        if start < USER_BASE || end > USER_STACK_LIMIT {
+            return Err(LoadError::OutsideUserSpace);
+        }
+
+        let mut flags = EntryFlags::User as usize;
+        if segment.flags & elf::PF_R != 0 {
+            flags |= EntryFlags::Read as usize;
+        }
+        // Write-only pages are reserved in RISC-V, so writable implies readable
+        if segment.flags & elf::PF_W != 0 {
+            flags |= EntryFlags::Read as usize | EntryFlags::Write as usize;
+        }
+        if segment.flags & elf::PF_X != 0 {
+            flags |= EntryFlags::Execute as usize;
+        }
+
+        let region_start = start & !(PAGE_SIZE - 1);
+        let region_end = page::align_val(end, PAGE_ORDER);
+        let backing = Backing::File {
+            data: segment.data,
+            offset: start - region_start,
+        };
+        self.address_space
+            .map_region(region_start, region_end, flags, backing)
+            .map_err(|err| match err {
+                VmaError::Overlap => LoadError::OverlappingSegments,
+                _ => LoadError::OutsideUserSpace,
+            })?;
+        // Loaded up front, so a broken executable fails here instead of at some later fault.
+        // Any access the segment allows will do to fill it in.
+        let access = if flags & EntryFlags::Execute as usize != 0 {
+            Access::Execute
+        } else {
+            Access::Load
+        };
+        self.address_space
+            .populate(region_start, region_end, access)
+            .map_err(|_| LoadError::OutOfMemory)
+    }
+
+    /// Maps the user stack and lays out argc, argv and envp at its top, like the System V ABI does:
+    /// argc, the NULL terminated argv and envp pointer arrays and above that the strings themselves.
+    /// Returns the initial stack pointer.
+    fn setup_user_stack(&mut self, argv: &[&str], envp: &[&str]) -> Result<usize, LoadError> {
+        let strings_size: usize = argv.iter().chain(envp).map(|s| s.len() + 1).sum();
+        let pointer_count = 1 + argv.len() + 1 + envp.len() + 1;
+        let needed = strings_size + pointer_count * size_of::<usize>() + 16;
+        if needed > USER_STACK_PAGES * PAGE_SIZE {
+            return Err(LoadError::ArgumentsTooLong);
+        }
+
+        self.address_space
+            .map_region(
+                USER_STACK_LIMIT,
+                USER_STACK_TOP,
+                USER_RW,
+                Backing::Anonymous,
+            )
+            .map_err(|_| LoadError::OutsideUserSpace)?;
+        self.address_space
+            .populate(USER_STACK_BOTTOM, USER_STACK_TOP, Access::Store)
+            .map_err(|_| LoadError::OutOfMemory)?;
+
+        let mut sp = USER_STACK_TOP;
+        let mut pointers = Vec::with_capacity(pointer_count);
+        pointers.push(argv.len());
+        for strings in [argv, envp] {
+            for string in strings {
+                sp -= string.len() + 1;
+                self.copy_to_user(sp, string.as_bytes())
+                    .and_then(|_| self.copy_to_user(sp + string.len(), &[0]))
+                    .expect("User stack is mapped");
+                pointers.push(sp);
+            }
+            pointers.push(0);
+        }
+
+        sp = (sp - pointers.len() * size_of::<usize>()) & !0xF;
+        for (i, pointer) in pointers.iter().enumerate() {
+            self.copy_to_user(sp + i * size_of::<usize>(), &pointer.to_le_bytes())
+                .expect("User stack is mapped");
+        }
+        Ok(sp)
+    }
+
+    /// Copies `buf.len()` bytes starting at user address `addr` into `buf`.
+    /// Fails if any of it is not readable for user mode. Pages not touched yet are filled in first.
+    pub fn copy_from_user(&mut self, addr: usize, buf: &mut [u8]) -> Result<(), SyscallError> {
+        self.for_each_user_chunk(addr, buf.len(), Access::Load, |phys, offset, len| unsafe {
+            core::ptr::copy_nonoverlapping(phys, buf[offset..].as_mut_ptr(), len);
+        })
+    }
+
+    /// Copies `buf` to user address `addr`.
+    /// Fails if any of it is not writable for user mode. Pages not touched yet are filled in first.
+    pub fn copy_to_user(&mut self, addr: usize, buf: &[u8]) -> Result<(), SyscallError> {
+        self.for_each_user_chunk(addr, buf.len(), Access::Store, |phys, offset, len| unsafe {
+            core::ptr::copy_nonoverlapping(buf[offset..].as_ptr(), phys, len);
+        })
+    }
+
+    /// Splits the user range [addr, addr + len) into the parts that lie within one page,
+    /// and calls `f` with the physical address, offset in the range and length of each part.
+    fn for_each_user_chunk(
+        &mut self,
+        addr: usize,
+        len: usize,
+        access: Access,
+        mut f: impl FnMut(*mut u8, usize, usize),
+    ) -> Result<(), SyscallError> {
+        let flags = access.required_flags();
+        let mut done = 0;
+        while done < len {
+            let virt_address = addr.checked_add(done).ok_or(SyscallError::BadAddress)?;
+            let phys_address = match self.address_space.translate(virt_address, flags) {
+                Some(phys_address) => phys_address,
+                None => {
+                    self.address_space
+                        .handle_fault(virt_address, access)
+                        .map_err(|_| SyscallError::BadAddress)?;
+                    self.address_space
+                        .translate(virt_address, flags)
+                        .ok_or(SyscallError::BadAddress)?
+                }
+            };
+            // The kernel only reaches RAM, not e.g. device registers mapped into the process
+            let phys_address = phys_address.0 as usize;
+            if !arch::is_direct_mapped(phys_address) {
+                return Err(SyscallError::BadAddress);
+            }
+            // Only up to the end of the page, the next one might be somewhere else entirely
+            let chunk = (PAGE_SIZE - virt_address % PAGE_SIZE).min(len - done);
+            f(arch::phys_to_virt(phys_address) as *mut u8, done, chunk);
+            done += chunk;
         }
+        Ok(())
     }
 }
 
@@ -43,6 +311,12 @@ pub enum ProcessState {
     #[default]
     Unused,
     Runnable,
+    /// Runnable again once the `time` CSR reaches `until`.
+    Sleeping {
+        until: u64,
+    },
+    /// Waiting in a `WaitQueue` until it is woken.
+    Blocked,
     Exited,
     KernelReserved,
 }
@@ -0,0 +1,67 @@
+/// Fixed size FIFO of bytes. `push` fails on a full buffer, `push_overwrite` drops the oldest byte instead.
+pub struct RingBuffer<const N: usize> {
+    data: [u8; N],
+    head: usize,
+    len: usize,
+}
+
+impl<const N: usize> RingBuffer<N> {
+    pub const fn new() -> Self {
+        RingBuffer {
+            data: [0; N],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    /// Returns false when the buffer is full and the byte was dropped.
+    pub fn push(&mut self, byte: u8) -> bool {
+        if self.is_full() {
+            return false;
+        }
+        self.data[(self.head + self.len) % N] = byte;
+        self.len += 1;
+        true
+    }
+
+    /// Pushes `byte`, making room by dropping the oldest byte if needed.
+    pub fn push_overwrite(&mut self, byte: u8) {
+        if self.is_full() {
+            self.pop();
+        }
+        self.push(byte);
+    }
+
+    pub fn pop(&mut self) -> Option<u8> {
+        if self.is_empty() {
+            return None;
+        }
+        let byte = self.data[self.head];
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        Some(byte)
+    }
+
+    /// The bytes from oldest to newest, without removing them.
+    pub fn iter(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..self.len).map(|i| self.data[(self.head + i) % N])
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == N
+    }
+}
+
+impl<const N: usize> Default for RingBuffer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
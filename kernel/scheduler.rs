@@ -1,15 +1,46 @@
-use super::process::{CpuContext, Process, ProcessState};
-use crate::println;
-use alloc::{boxed::Box, collections::vec_deque::VecDeque};
-use core::{arch::global_asm, fmt::Display};
+use super::process::{CpuContext, LoadError, Process, ProcessState, UserMemory};
+use crate::cpu::{self, MAX_CPUS};
+use crate::sync::Mutex;
+use crate::{arch, debug, spinlock::IrqSpinLock, trace};
+use alloc::{collections::vec_deque::VecDeque, sync::Arc, vec::Vec};
+use core::{
+    arch::global_asm,
+    fmt::Display,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
 const MAX_PROCESSES: usize = 2;
+const DEFAULT_TIME_SLICE_MS: usize = 50;
+
+pub static SCHEDULER: IrqSpinLock<Scheduler> = IrqSpinLock::new(Scheduler::new());
+
+/// Kept outside of the scheduler lock, since the timer has to be re-armed even when the lock is taken.
+static TIME_SLICE_MS: AtomicUsize = AtomicUsize::new(DEFAULT_TIME_SLICE_MS);
 
 pub struct Scheduler {
     processes: VecDeque<Process>,
     next_proc_id: u32,
+    /// What each hart runs, indexed by `Cpu::index`.
+    cpus: [CpuProcesses; MAX_CPUS],
+    /// Exited processes whose kernel stack cannot be freed from within the switch.
+    zombies: Vec<Process>,
+}
+
+/// The processes of one hart. Each hart has its own idle process, which is its boot code.
+struct CpuProcesses {
     current_running: Option<Process>,
     previously_running: Option<Process>,
+    idle: Option<Process>,
+}
+
+impl CpuProcesses {
+    const fn new() -> Self {
+        CpuProcesses {
+            current_running: None,
+            previously_running: None,
+            idle: None,
+        }
+    }
 }
 
 impl Default for Scheduler {
@@ -44,126 +75,426 @@ impl Display for ProcessInfo {
 }
 
 impl Scheduler {
-    pub fn new() -> Self {
-        let processes: VecDeque<Process> = VecDeque::new();
+    pub const fn new() -> Self {
         Scheduler {
-            processes,
+            processes: VecDeque::new(),
             next_proc_id: 1,
-            current_running: None,
-            previously_running: None,
+            cpus: [const { CpuProcesses::new() }; MAX_CPUS],
+            zombies: Vec::new(),
         }
     }
 
     /// TODO: Make this mandatory from within the type system
     pub fn init(&mut self) {
         self.processes.reserve(MAX_PROCESSES);
-        self.current_running = Some(Self::create_idle_process());
+        self.zombies.reserve(MAX_PROCESSES);
+        arch::register_trap_handler(
+            arch::TrapCause::Interrupt(arch::Interrupt::SupervisorTimer),
+            |_, _| Self::preempt(),
+        );
+        self.init_cpu();
+    }
+
+    /// Gets the calling hart ready to schedule, `init` does this for the boot hart.
+    pub fn init_cpu(&mut self) {
+        // The boot code becomes the idle process: its context is stored here on the first switch.
+        self.cpu().current_running = Some(Self::create_idle_process());
+        arch::enable_timer_interrupts();
+    }
+
+    /// The processes of the calling hart.
+    fn cpu(&mut self) -> &mut CpuProcesses {
+        &mut self.cpus[cpu::current().index()]
+    }
+
+    pub fn set_time_slice(ms: usize) {
+        TIME_SLICE_MS.store(ms, Ordering::Relaxed);
+    }
+
+    /// Starts preemptive scheduling on the calling hart and turns the caller into its idle process.
+    pub fn start() -> ! {
+        arch::set_timer_in(TIME_SLICE_MS.load(Ordering::Relaxed));
+        arch::enable_interrupts();
+        loop {
+            Self::reap_zombies();
+            Self::yield_control();
+            arch::wait_for_interrupt();
+        }
+    }
+
+    pub fn exit_process() -> ! {
+        arch::disable_interrupts();
+        match SCHEDULER.lock().cpu().current_running.as_mut() {
+            Some(current) => current.state = ProcessState::Exited,
+            None => panic!("Exiting a unexisting process"),
+        }
+        Self::yield_control();
+        unreachable!("Exited process was scheduled again");
+    }
+
+    /// Puts the current process to sleep for at least `ms` milliseconds.
+    /// Sleeping processes are only woken up when the scheduler runs, so this is rounded up to the time slice.
+    pub fn sleep(ms: usize) {
+        let until = arch::read_time() + arch::TIMEBASE_FREQUENCY / 1000 * ms as u64;
+        Self::with_current(|current| {
+            // Killed from another hart in the meantime
+            if current.state == ProcessState::Runnable {
+                current.state = ProcessState::Sleeping { until };
+            }
+        });
+        Self::yield_control();
+    }
+
+    /// Marks the current process blocked: once it yields it only runs again after `wake`. Returns its pid,
+    /// or `None` when it was killed from another hart, in which case it must not wait.
+    pub fn block_current() -> Option<u32> {
+        Self::with_current(|current| {
+            assert!(
+                current.state != ProcessState::KernelReserved,
+                "The idle process cannot block"
+            );
+            // Killed from another hart in the meantime
+            if current.state != ProcessState::Runnable {
+                return None;
+            }
+            current.state = ProcessState::Blocked;
+            Some(current.pid)
+        })
     }
 
-    pub fn exit_process(&mut self) {
-        if self.current_running.is_none() {
-            panic!("Exiting a unexisting process")
+    /// Takes back `block_current`, if nobody woke the process yet.
+    pub fn unblock_current() {
+        Self::with_current(|current| {
+            if current.state == ProcessState::Blocked {
+                current.state = ProcessState::Runnable;
+            }
+        });
+    }
+
+    /// Makes the process with `pid` runnable again, returning whether it was blocked.
+    pub fn wake(&mut self, pid: u32) -> bool {
+        let on_cpus = self.cpus.iter_mut().flat_map(|cpu| {
+            cpu.current_running
+                .iter_mut()
+                .chain(cpu.previously_running.iter_mut())
+        });
+        match on_cpus
+            .chain(self.processes.iter_mut())
+            .find(|proc| proc.pid == pid)
+        {
+            Some(proc) if proc.state == ProcessState::Blocked => {
+                proc.state = ProcessState::Runnable;
+                true
+            }
+            _ => false,
         }
-        self.current_running.as_mut().unwrap().state = ProcessState::Exited;
-        self.yield_control();
     }
+
+    pub fn current_pid() -> u32 {
+        Self::with_current(|current| current.pid)
+    }
+
+    /// Runs `f` on the current process, with the scheduler locked.
+    pub fn with_current<R>(f: impl FnOnce(&mut Process) -> R) -> R {
+        let mut scheduler = SCHEDULER.lock();
+        let current = scheduler
+            .cpu()
+            .current_running
+            .as_mut()
+            .expect("No process is running");
+        f(current)
+    }
+
+    /// Runs `f` on the user memory of the current process, locked on its own instead of with the scheduler,
+    /// since it can fault in pages and copy a lot. It may block, so not from the idle loop or interrupts.
+    pub fn with_current_memory<R>(f: impl FnOnce(&mut UserMemory) -> R) -> R {
+        // Not cloned: this process could be killed while blocked and would never drop the clone.
+        // The process keeps the memory alive itself, and it is only freed once it stopped running for good.
+        let memory = Self::with_current(|current| Arc::as_ptr(&current.memory));
+        //Safety: See above
+        let memory = unsafe { &*memory };
+        f(&mut memory.lock())
+    }
+
+    /// Info on every process, starting with the running ones.
+    pub fn process_infos(&self) -> Vec<ProcessInfo> {
+        let running = self.cpus.iter().flat_map(|cpu| {
+            cpu.current_running
+                .iter()
+                .chain(cpu.previously_running.iter())
+        });
+        let idle = self.cpus.iter().flat_map(|cpu| cpu.idle.iter());
+        running
+            .chain(self.processes.iter())
+            .chain(idle)
+            .map(ProcessInfo::from)
+            .collect()
+    }
+
+    /// The user memory of the process with `pid`, if there is one.
+    pub fn memory_of(&self, pid: u32) -> Option<Arc<Mutex<UserMemory>>> {
+        let on_cpus = self.cpus.iter().flat_map(|cpu| {
+            cpu.current_running
+                .iter()
+                .chain(cpu.previously_running.iter())
+        });
+        on_cpus
+            .chain(self.processes.iter())
+            .find(|proc| proc.pid == pid)
+            .map(|proc| proc.memory.clone())
+    }
+
+    /// Ends the process with `pid`, returning whether there was one to end.
+    /// The calling process and the idle processes cannot be killed, a process ends itself through `exit_process`.
+    pub fn kill(&mut self, pid: u32) -> bool {
+        let this_cpu = cpu::current().index();
+        let on_cpus = self.cpus.iter_mut().enumerate().flat_map(|(index, cpu)| {
+            // The caller is the current process of its own hart
+            let current = cpu.current_running.as_mut().filter(|_| index != this_cpu);
+            current.into_iter().chain(cpu.previously_running.as_mut())
+        });
+        for proc in on_cpus {
+            if proc.pid == pid && proc.state != ProcessState::KernelReserved {
+                // Released into the zombies by its hart on the next switch
+                proc.state = ProcessState::Exited;
+                return true;
+            }
+        }
+        match self.processes.iter().position(|proc| proc.pid == pid) {
+            Some(index) => {
+                let mut proc = self.processes.remove(index).unwrap();
+                proc.state = ProcessState::Exited;
+                self.zombies.push(proc);
+                true
+            }
+            None => false,
+        }
+    }
+
     pub fn schedule_process(&mut self, entry_point: usize) -> ProcessInfo {
-        let new_proc = Process {
-            pid: self.next_proc_id,
-            state: ProcessState::Runnable,
-            ..Default::default()
-        };
-        println!(
+        let new_proc = Process::new(self.next_proc_id, ProcessState::Runnable);
+        debug!(
             "Process {}: kernel_stack at {:p}",
             new_proc.pid,
             new_proc.kernel_stack.as_ptr()
         );
         self.next_proc_id += 1;
         self.processes.push_back(new_proc);
+        // Make sure a process exiting never has to allocate from within the switch
+        self.zombies.reserve(self.processes.len() + 1);
         let new_proc = self.processes.back_mut().unwrap();
         Self::init_process(new_proc, entry_point);
         ProcessInfo::from(new_proc)
     }
 
-    fn shedule_idle() {
-        panic!("Kernel Idle")
+    /// Schedules a process running the executable `elf` in user mode. See `Process::load_elf`.
+    pub fn schedule_user_process(
+        &mut self,
+        elf: &'static [u8],
+        argv: &[&str],
+        envp: &[&str],
+    ) -> Result<ProcessInfo, LoadError> {
+        let mut new_proc = Process::new(self.next_proc_id, ProcessState::Runnable);
+        new_proc.load_elf(elf, argv, envp)?;
+        debug!(
+            "Process {}: user process entering at {:#x}, kernel_stack at {:p}",
+            new_proc.pid,
+            new_proc.user_entry,
+            new_proc.kernel_stack.as_ptr()
+        );
+        self.next_proc_id += 1;
+        Self::init_user_process(&mut new_proc);
+        let info = ProcessInfo::from(&new_proc);
+        self.processes.push_back(new_proc);
+        self.zombies.reserve(self.processes.len() + 1);
+        Ok(info)
     }
 
     fn create_idle_process() -> Process {
-        let mut idle_process = Process {
-            pid: 0,
-            state: ProcessState::KernelReserved,
-            kernel_stack: Box::new([0; 8192]),
-            context: CpuContext::default(),
-        };
-        let idle_entry = Self::shedule_idle as *const () as usize;
-        Self::init_process(&mut idle_process, idle_entry);
-        idle_process
+        Process::new(0, ProcessState::KernelReserved)
     }
+
     fn init_process(proc: &mut Process, entry_point: usize) {
         unsafe {
             let sp = proc.kernel_stack.as_mut_ptr().add(proc.kernel_stack.len());
             assert!(
-                sp as usize % 16 == 0,
+                (sp as usize).is_multiple_of(16),
                 "stack_pointer is not 16-byte aligned"
             );
             proc.context.sp = sp as usize;
-            proc.context.ra = entry_point;
+            proc.context.ra = __process_entry as *const () as usize;
+            proc.context.s0 = entry_point;
         }
     }
 
-    pub extern "C" fn yield_control(&mut self) {
-        if self.current_running.is_none() {
-            panic!("Cannot yield without having inited the sheduler")
+    /// The first switch to a user process lands in `__user_entry`, which drops to user mode.
+    fn init_user_process(proc: &mut Process) {
+        Self::init_process(proc, proc.user_entry);
+        proc.context.ra = __user_entry as *const () as usize;
+        proc.context.s1 = proc.user_stack;
+    }
+
+    fn reap_zombies() {
+        loop {
+            // Pop under the lock, but drop outside of it, since freeing the stack takes the page allocator lock
+            let zombie = SCHEDULER.lock().zombies.pop();
+            match zombie {
+                Some(zombie) => drop(zombie),
+                None => break,
+            }
         }
+    }
+
+    /// Gives up the cpu to the next runnable process.
+    pub fn yield_control() {
+        let interrupts = arch::disable_interrupts();
+        let switch = SCHEDULER.lock().switch_next(false);
+        if let Some(switch) = switch {
+            Self::switch_context(switch);
+        }
+        arch::restore_interrupts(interrupts);
+    }
 
+    /// Called from the timer interrupt, with interrupts disabled.
+    pub fn preempt() {
+        arch::set_timer_in(TIME_SLICE_MS.load(Ordering::Relaxed));
+        // The interrupted code cannot hold the scheduler, interrupts are off while it is locked
+        let switch = SCHEDULER.lock().switch_next(true);
+        if let Some(switch) = switch {
+            Self::switch_context(switch);
+        }
+    }
+
+    /// Picks the next process to run and returns what is needed to switch to it, if any.
+    /// The contexts stay valid after unlocking, since interrupts are disabled until the switch is done.
+    /// A `preempted` process did not choose to give up the cpu, so it stays runnable even if blocked:
+    /// it may be past its last check of what it waits for, with nobody left to wake it.
+    fn switch_next(&mut self, preempted: bool) -> Option<Switch> {
+        let cpu = &mut self.cpus[cpu::current().index()];
+        let current = cpu
+            .current_running
+            .as_mut()
+            .expect("Cannot yield without having inited the sheduler");
+        if preempted && current.state == ProcessState::Blocked {
+            current.state = ProcessState::Runnable;
+        }
+        let keep_current = matches!(
+            current.state,
+            ProcessState::Runnable | ProcessState::KernelReserved
+        );
         //TODO: This previously_running thing is a hack to account for a fact
         //we don't yet have an ARC type that can allow use to still use the previous when doing context switch
-        if let Some(prev) = self.previously_running.take() {
-            if prev.state == ProcessState::Runnable {
-                self.processes.push_back(prev);
+        // Its context is saved by now, so other harts may pick it up
+        if let Some(prev) = cpu.previously_running.take() {
+            match prev.state {
+                ProcessState::Runnable | ProcessState::Sleeping { .. } | ProcessState::Blocked => {
+                    self.processes.push_back(prev)
+                }
+                ProcessState::KernelReserved => cpu.idle = Some(prev),
+                _ => self.zombies.push(prev),
             }
         }
 
-        self.previously_running = self.current_running.take();
-        let proc = self.processes.pop_front();
-        self.current_running = match proc {
-            None if self.previously_running.as_ref().unwrap().state == ProcessState::Runnable => {
-                println!("Nothing in the process-queue to yield to, but previous still runnable");
-                self.previously_running.take()
+        let now = arch::read_time();
+        for proc in self.processes.iter_mut() {
+            if let ProcessState::Sleeping { until } = proc.state {
+                if until <= now {
+                    proc.state = ProcessState::Runnable;
+                }
             }
+        }
+        let ready = self
+            .processes
+            .iter()
+            .position(|proc| proc.state == ProcessState::Runnable);
+        let next = match ready.and_then(|i| self.processes.remove(i)) {
+            Some(p) => p,
+            None if keep_current => return None,
             None => {
-                println!("Nothing in the process-queue to yield to, going idle!");
-                Some(Self::create_idle_process())
+                trace!("Nothing in the process-queue to yield to, going idle!");
+                cpu.idle.take().expect("Idle process is missing")
             }
-            Some(p) => Some(p),
         };
-        println!(
-            "Switching from {} to {}",
-            self.previously_running.as_ref().unwrap().pid,
-            self.current_running.as_ref().unwrap().pid
-        );
-        Self::switch_context(
-            &self.previously_running.as_mut().unwrap().context,
-            &self.current_running.as_mut().unwrap().context,
-        );
+        cpu.previously_running = cpu.current_running.replace(next);
+        let prev = cpu.previously_running.as_mut().unwrap();
+        let next = cpu.current_running.as_ref().unwrap();
+        trace!("Switching from {} to {}", prev.pid, next.pid);
+        Some(Switch {
+            prev_context: &mut prev.context,
+            next_context: &next.context,
+            next_satp: next.satp(),
+        })
     }
 
-    #[no_mangle]
-    extern "C" fn switch_context(prev_context: &CpuContext, next_context: &CpuContext) {
-        println!(
-            "Switching from sp: {:#x} and ra: {:#x} to sp: {:#x} and ra:{:#x}",
-            prev_context.sp, prev_context.ra, next_context.sp, next_context.ra,
-        );
-
+    fn switch_context(switch: Switch) {
+        let Switch {
+            prev_context,
+            next_context,
+            next_satp,
+        } = switch;
+        // Kernel mappings are shared by all page tables, so we can keep running on the new one
+        next_satp.switch();
         unsafe {
+            trace!(
+                "Switching from sp: {:#x} and ra: {:#x} to sp: {:#x} and ra:{:#x}",
+                (*prev_context).sp,
+                (*prev_context).ra,
+                (*next_context).sp,
+                (*next_context).ra,
+            );
             __switch_context(prev_context, next_context);
         }
     }
 }
+
+/// Taken out of the scheduler by `switch_next`, so the switch itself can happen unlocked.
+struct Switch {
+    prev_context: *mut CpuContext,
+    next_context: *const CpuContext,
+    next_satp: arch::Satp,
+}
+
+/// Exit path for processes returning from their entry point.
+extern "C" fn process_return() -> ! {
+    Scheduler::exit_process();
+}
+
 extern "C" {
-    fn __switch_context(current: &CpuContext, to: &CpuContext);
+    fn __switch_context(current: *mut CpuContext, to: *const CpuContext);
+    fn __process_entry();
+    fn __user_entry();
 }
+
+// First code a new process runs: it is switched to with interrupts disabled, so enable them
+// and call the entry point stored in s0.
+global_asm!(
+    "__process_entry:",
+    "csrsi sstatus, 2",
+    "jalr s0",
+    "j {process_return}",
+    process_return = sym process_return,
+);
+
+// First code a new user process runs: enter user mode at s0 with the user stack in s1.
+// Traps from user mode land on top of this (still empty) kernel stack, via sscratch,
+// right below a slot holding the tp of the hart, see `kernel_entry`.
+global_asm!(
+    "__user_entry:",
+    "csrw sepc, s0",
+    // SPP = 0 (return to user mode), SPIE = 1 (interrupts on after sret)
+    "li t0, 1 << 8",
+    "csrc sstatus, t0",
+    "li t0, 1 << 5",
+    "csrs sstatus, t0",
+    "addi sp, sp, -16",
+    "sw tp, 0(sp)",
+    "csrw sscratch, sp",
+    "mv sp, s1",
+    "li s0, 0",
+    "li s1, 0",
+    "li tp, 0",
+    "sret",
+);
 global_asm!(
     "__switch_context:",
     "sw ra, 0(a0)",
@@ -1,5 +1,11 @@
+use crate::cpu;
+#[cfg(feature = "lock-debug")]
+use crate::lockdep::LockDebug;
+#[cfg(feature = "lock-debug")]
+use core::panic::Location;
 use core::{
     cell::UnsafeCell,
+    mem::ManuallyDrop,
     ops::{Deref, DerefMut},
     sync::atomic::{AtomicBool, Ordering},
 };
@@ -37,6 +43,8 @@ unsafe impl<T> Sync for Guard<'_, T> where T: Sync {}
 
 pub struct SpinLock<T> {
     locked: AtomicBool,
+    #[cfg(feature = "lock-debug")]
+    debug: LockDebug,
     value: UnsafeCell<T>,
 }
 
@@ -46,18 +54,103 @@ impl<T> SpinLock<T> {
     pub const fn new(value: T) -> Self {
         Self {
             locked: AtomicBool::new(false),
+            #[cfg(feature = "lock-debug")]
+            debug: LockDebug::new(),
             value: UnsafeCell::new(value),
         }
     }
 
-    pub fn lock(&self) -> Guard<T> {
+    #[cfg_attr(feature = "lock-debug", track_caller)]
+    pub fn lock(&self) -> Guard<'_, T> {
+        #[cfg(feature = "lock-debug")]
+        let site = Location::caller();
+        #[cfg(feature = "lock-debug")]
+        self.debug.acquire(self.address(), site);
+        #[cfg(feature = "lock-debug")]
+        let mut spins = 0;
         while self.locked.swap(true, Ordering::Acquire) {
+            #[cfg(feature = "lock-debug")]
+            {
+                spins += 1;
+                self.debug.spinning(self.address(), site, spins);
+            }
             core::hint::spin_loop();
         }
+        #[cfg(feature = "lock-debug")]
+        self.debug.acquired(self.address(), site);
         Guard { lock: self }
     }
 
+    /// Takes the lock only if it is free right now. Used from trap context,
+    /// where spinning on a lock held by the interrupted code would never end.
+    #[cfg_attr(feature = "lock-debug", track_caller)]
+    pub fn try_lock(&self) -> Option<Guard<'_, T>> {
+        if self.locked.swap(true, Ordering::Acquire) {
+            return None;
+        }
+        #[cfg(feature = "lock-debug")]
+        self.debug.acquired(self.address(), Location::caller());
+        Some(Guard { lock: self })
+    }
+
     fn unlock(&self) {
+        #[cfg(feature = "lock-debug")]
+        self.debug.released(self.address());
         self.locked.store(false, Ordering::Release);
     }
+
+    /// What lock debugging knows this lock by.
+    #[cfg(feature = "lock-debug")]
+    fn address(&self) -> usize {
+        self as *const _ as usize
+    }
+}
+
+/// A `SpinLock` that keeps interrupts off on this hart while it is held, so a trap handler
+/// never spins on a lock taken by the code it interrupted. These nest: interrupts only come
+/// back on once the hart released all of them, and only if they were on before the first.
+pub struct IrqSpinLock<T> {
+    lock: SpinLock<T>,
+}
+
+pub struct IrqGuard<'a, T> {
+    guard: ManuallyDrop<Guard<'a, T>>,
+}
+
+impl<T> Deref for IrqGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for IrqGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for IrqGuard<'_, T> {
+    fn drop(&mut self) {
+        // Unlock before interrupts can come in again
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        cpu::pop_interrupts_off();
+    }
+}
+
+impl<T> IrqSpinLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            lock: SpinLock::new(value),
+        }
+    }
+
+    #[cfg_attr(feature = "lock-debug", track_caller)]
+    pub fn lock(&self) -> IrqGuard<'_, T> {
+        cpu::push_interrupts_off();
+        IrqGuard {
+            guard: ManuallyDrop::new(self.lock.lock()),
+        }
+    }
 }
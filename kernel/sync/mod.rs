@@ -0,0 +1,9 @@
+//! Blocking synchronization primitives. Unlike the spinlocks, a process waiting on these
+//! is parked in the scheduler until it is woken, so it does not burn the cpu.
+//! They can only be waited on from a process, which includes its syscalls and page faults,
+//! not from interrupt handlers or the idle loop.
+mod mutex;
+mod wait_queue;
+
+pub use mutex::{Mutex, MutexGuard};
+pub use wait_queue::WaitQueue;
@@ -0,0 +1,98 @@
+use crate::scheduler::{Scheduler, SCHEDULER};
+use crate::spinlock::IrqSpinLock;
+use alloc::collections::vec_deque::VecDeque;
+
+/// Processes waiting for something, woken in the order they started waiting.
+pub struct WaitQueue {
+    waiters: IrqSpinLock<VecDeque<u32>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        WaitQueue {
+            waiters: IrqSpinLock::new(VecDeque::new()),
+        }
+    }
+
+    /// Blocks the current process until `condition` holds. It is checked again every time we are woken.
+    pub fn wait_until(&self, mut condition: impl FnMut() -> bool) {
+        loop {
+            if condition() {
+                return;
+            }
+            if !self.prepare_to_wait() {
+                // Killed, the yield in here hands us to the reaper
+                Scheduler::exit_process();
+            }
+            // A wake from here on makes the process runnable again, so the yield returns right away
+            let done = condition();
+            if !done {
+                Scheduler::yield_control();
+            }
+            // Taken off the queue by a waker we did not need: someone else might
+            if self.finish_wait() && done {
+                self.wake_one();
+            }
+            if done {
+                return;
+            }
+        }
+    }
+
+    /// Marks the current process blocked and queues it, but leaves the yield to the caller.
+    /// Whatever the caller waits for has to be checked after this, so a wake in between is not lost.
+    /// Returns false without queueing when the process was killed, it should not wait then.
+    pub fn prepare_to_wait(&self) -> bool {
+        // Blocked before queued, so a waker that finds us always has something to wake.
+        // Interrupts are off while holding the queue: preempted in between, nobody could wake us.
+        let mut waiters = self.waiters.lock();
+        match Scheduler::block_current() {
+            Some(pid) => {
+                waiters.push_back(pid);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Undoes `prepare_to_wait`, for when the process was woken or did not have to wait after all.
+    /// Returns whether a waker already took the process off the queue.
+    pub fn finish_wait(&self) -> bool {
+        let pid = Scheduler::current_pid();
+        let mut waiters = self.waiters.lock();
+        let queued = waiters.len();
+        waiters.retain(|&waiter| waiter != pid);
+        let woken = waiters.len() == queued;
+        drop(waiters);
+        Scheduler::unblock_current();
+        woken
+    }
+
+    /// Wakes the longest waiting process, returning whether there was one.
+    pub fn wake_one(&self) -> bool {
+        loop {
+            let Some(pid) = self.waiters.lock().pop_front() else {
+                return false;
+            };
+            // Skip processes that were killed or woken already
+            if SCHEDULER.lock().wake(pid) {
+                return true;
+            }
+        }
+    }
+
+    /// Wakes every process waiting right now. Those that queue again once woken wait for the next wake.
+    pub fn wake_all(&self) {
+        let waiters = core::mem::take(&mut *self.waiters.lock());
+        let mut scheduler = SCHEDULER.lock();
+        for pid in waiters {
+            scheduler.wake(pid);
+        }
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,65 @@
+use crate::arch::{self, Exception, TrapCause, TrapFrame};
+use crate::scheduler::Scheduler;
+use crate::{info, print};
+use alloc::{string::String, vec};
+use oxiv_abi::{encode_result, Syscall, SyscallError, SyscallResult, STDOUT};
+
+/// Longest buffer a single `write` accepts, so a process cannot make us allocate all memory.
+const MAX_WRITE_LEN: usize = 16 * 1024;
+
+pub fn init() {
+    arch::register_trap_handler(
+        TrapCause::Exception(Exception::EnvironmentCallFromUMode),
+        handle_syscall,
+    );
+}
+
+fn handle_syscall(frame: &mut TrapFrame, _stval: usize) {
+    frame.skip_ecall();
+    let args = frame.syscall_args();
+    // Syscalls can take a while, so let the timer preempt us. Traps from the kernel are fine, our frame is saved.
+    arch::enable_interrupts();
+    let result =
+        Syscall::try_from(frame.syscall_number()).and_then(|syscall| dispatch(syscall, args));
+    arch::disable_interrupts();
+    frame.set_syscall_result(encode_result(result));
+}
+
+fn dispatch(syscall: Syscall, args: [usize; 6]) -> SyscallResult {
+    match syscall {
+        Syscall::Write => sys_write(args[0], args[1], args[2]),
+        Syscall::Exit => sys_exit(args[0]),
+        Syscall::Yield => {
+            Scheduler::yield_control();
+            Ok(0)
+        }
+        Syscall::GetPid => Ok(Scheduler::current_pid() as usize),
+        Syscall::Sleep => {
+            Scheduler::sleep(args[0]);
+            Ok(0)
+        }
+        Syscall::Sbrk => Scheduler::with_current_memory(|memory| memory.sbrk(args[0])),
+    }
+}
+
+fn sys_write(fd: usize, addr: usize, len: usize) -> SyscallResult {
+    if fd != STDOUT {
+        return Err(SyscallError::BadFileDescriptor);
+    }
+    if len > MAX_WRITE_LEN {
+        return Err(SyscallError::InvalidArgument);
+    }
+    let mut buf = vec![0u8; len];
+    Scheduler::with_current_memory(|memory| memory.copy_from_user(addr, &mut buf))?;
+    print!("{}", String::from_utf8_lossy(&buf));
+    Ok(len)
+}
+
+fn sys_exit(code: usize) -> ! {
+    info!(
+        "Process {} exited with code {}",
+        Scheduler::current_pid(),
+        code as isize
+    );
+    Scheduler::exit_process();
+}
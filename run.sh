@@ -3,9 +3,11 @@ set -xue
 
 # QEMU file path
 QEMU=qemu-system-riscv32
+# Number of harts, override with e.g. SMP=1 ./run.sh
+SMP=${SMP:-4}
 
 if [[ $# -gt 0 && "$1" == "--log" ]]; then
-    $QEMU -machine virt -bios default -nographic -serial mon:stdio -no-reboot -d unimp,guest_errors,int,cpu_reset -D qemu.log -kernel target/riscv32imac-unknown-none-elf/release/oxiv_riscv32
+    $QEMU -machine virt -smp $SMP -bios default -nographic -serial mon:stdio -d unimp,guest_errors,int,cpu_reset -D qemu.log -kernel target/riscv32imac-unknown-none-elf/release/oxiv_riscv32
 else
-    $QEMU -machine virt -bios default -nographic -serial mon:stdio -no-reboot -kernel target/riscv32imac-unknown-none-elf/release/oxiv_riscv32
+    $QEMU -machine virt -smp $SMP -bios default -nographic -serial mon:stdio -kernel target/riscv32imac-unknown-none-elf/release/oxiv_riscv32
 fi
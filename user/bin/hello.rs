@@ -0,0 +1,35 @@
+#![no_std]
+#![no_main]
+
+use oxiv_user::{println, Args};
+
+oxiv_user::entry!(main);
+
+/// Says hello a few times, taking a nap in between, and exits with its pid.
+/// On the way it puts a greeting on the heap, which the kernel backs on first touch.
+fn main(args: Args) -> isize {
+    let pid = oxiv_user::getpid();
+    for arg in args.args() {
+        println!("[{}] arg: {}", pid, arg);
+    }
+    for var in args.env() {
+        println!("[{}] env: {}", pid, var);
+    }
+    let greeting = b"Hello from the heap!";
+    if let Ok(heap) = oxiv_user::sbrk(greeting.len()) {
+        let heap = unsafe {
+            core::ptr::copy_nonoverlapping(greeting.as_ptr(), heap, greeting.len());
+            core::slice::from_raw_parts(heap, greeting.len())
+        };
+        println!(
+            "[{}] {}",
+            pid,
+            core::str::from_utf8(heap).unwrap_or("<garbled heap>")
+        );
+    }
+    for i in 0..3 {
+        println!("[{}] Hello from user mode! ({})", pid, i);
+        oxiv_user::sleep(100);
+    }
+    pid as isize
+}
@@ -0,0 +1,19 @@
+#![no_std]
+#![no_main]
+
+use oxiv_user::{println, Args};
+
+oxiv_user::entry!(main);
+
+/// Busy loops without ever yielding, so it only gives up the cpu when the timer preempts it.
+fn main(_args: Args) -> isize {
+    let pid = oxiv_user::getpid();
+    for i in 0..3 {
+        println!("[{}] Spinning ({})", pid, i);
+        let mut counter = 0usize;
+        while counter < 5_000_000 {
+            counter = core::hint::black_box(counter + 1);
+        }
+    }
+    0
+}
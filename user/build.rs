@@ -0,0 +1,6 @@
+fn main() {
+    // Use the user program linker script. The kernel builds us from another directory, so make the path absolute.
+    let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+    println!("cargo:rustc-link-arg-bins=-T{}/script.ld", manifest_dir);
+    println!("cargo:rerun-if-changed=script.ld");
+}
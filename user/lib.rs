@@ -0,0 +1,157 @@
+//! Runtime for user programs: entry point, syscall wrappers and printing.
+#![no_std]
+
+use core::arch::{asm, global_asm};
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use oxiv_abi::{decode_result, Syscall, SyscallError, SyscallResult, STDOUT};
+
+/// Declares the function the program starts in. It gets the arguments and returns the exit code.
+#[macro_export]
+macro_rules! entry {
+    ($main:path) => {
+        #[no_mangle]
+        extern "Rust" fn __oxiv_main(args: $crate::Args) -> isize {
+            let main: fn($crate::Args) -> isize = $main;
+            main(args)
+        }
+    };
+}
+
+extern "Rust" {
+    fn __oxiv_main(args: Args) -> isize;
+}
+
+// The kernel leaves argc at the top of the stack, followed by the NULL terminated argv and envp arrays.
+global_asm!(
+    ".pushsection .text._start, \"ax\"",
+    ".global _start",
+    "_start:",
+    "lw a0, 0(sp)",
+    "addi a1, sp, 4",
+    "slli a2, a0, 2",
+    "add a2, a2, a1",
+    "addi a2, a2, 4",
+    "call {start}",
+    ".popsection",
+    start = sym start,
+);
+
+extern "C" fn start(argc: usize, argv: *const *const u8, envp: *const *const u8) -> ! {
+    let args = Args { argc, argv, envp };
+    let code = unsafe { __oxiv_main(args) };
+    exit(code);
+}
+
+/// The arguments and environment the kernel started us with.
+pub struct Args {
+    argc: usize,
+    argv: *const *const u8,
+    envp: *const *const u8,
+}
+
+impl Args {
+    pub fn len(&self) -> usize {
+        self.argc
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.argc == 0
+    }
+
+    pub fn args(&self) -> impl Iterator<Item = &'static str> {
+        unsafe { c_str_array(self.argv) }
+    }
+
+    pub fn env(&self) -> impl Iterator<Item = &'static str> {
+        unsafe { c_str_array(self.envp) }
+    }
+}
+
+/// Iterates a NULL terminated array of NUL terminated strings.
+unsafe fn c_str_array(array: *const *const u8) -> impl Iterator<Item = &'static str> {
+    (0..)
+        .map(move |i| unsafe { *array.add(i) })
+        .take_while(|ptr| !ptr.is_null())
+        .map(|ptr| unsafe {
+            let len = (0..).take_while(|&i| *ptr.add(i) != 0).count();
+            core::str::from_utf8(core::slice::from_raw_parts(ptr, len)).unwrap_or("<invalid utf-8>")
+        })
+}
+
+fn syscall(syscall: Syscall, args: [usize; 3]) -> SyscallResult {
+    let mut a0 = args[0];
+    unsafe {
+        asm!(
+            "ecall",
+            inout("a0") a0,
+            in("a1") args[1],
+            in("a2") args[2],
+            in("a7") syscall as usize,
+            options(nostack),
+        );
+    }
+    decode_result(a0)
+}
+
+pub fn write(fd: usize, buf: &[u8]) -> SyscallResult {
+    syscall(Syscall::Write, [fd, buf.as_ptr() as usize, buf.len()])
+}
+
+pub fn exit(code: isize) -> ! {
+    let _ = syscall(Syscall::Exit, [code as usize, 0, 0]);
+    unreachable!("exit returned");
+}
+
+pub fn yield_now() {
+    let _ = syscall(Syscall::Yield, [0; 3]);
+}
+
+pub fn getpid() -> u32 {
+    syscall(Syscall::GetPid, [0; 3]).unwrap_or(0) as u32
+}
+
+pub fn sleep(ms: usize) {
+    let _ = syscall(Syscall::Sleep, [ms, 0, 0]);
+}
+
+/// Grows the heap by `increment` bytes, returning where the new part starts.
+/// It is backed by zeroed pages the first time it is touched.
+pub fn sbrk(increment: usize) -> Result<*mut u8, SyscallError> {
+    syscall(Syscall::Sbrk, [increment, 0, 0]).map(|end| end as *mut u8)
+}
+
+pub fn print_args(args: fmt::Arguments) {
+    let _ = Stdout.write_fmt(args);
+}
+
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ($crate::print_args(format_args!($($arg)*)));
+}
+
+struct Stdout;
+
+impl fmt::Write for Stdout {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        write(STDOUT, s.as_bytes())
+            .map(|_| ())
+            .map_err(|_| fmt::Error)
+    }
+}
+
+#[panic_handler]
+fn handle_panic(info: &PanicInfo) -> ! {
+    print!("Panic in process {}", getpid());
+    if let Some(location) = info.location() {
+        print!(" ({},{})", location.line(), location.column())
+    }
+    println!(": {}", info.message());
+    exit(-1);
+}